@@ -0,0 +1,270 @@
+use rustfft::num_complex::Complex;
+use crate::create_spectogram::{
+    ColumnStream, FrequencyScale, MagnitudeScale, Spectrogram, SpectrogramConfigError, SpectrogramUnits, WindowType,
+};
+use serde::{Serialize, Deserialize};
+
+/// Layout of the constant-Q transform.
+///
+/// Bin `k` is centred on `f_min * 2^(k / bins_per_octave)`, so a pitch shift moves
+/// every peak by the same number of bins instead of stretching the spectrum.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CqtConfig {
+    /// Centre of the lowest bin in Hz
+    pub f_min: f32,
+    /// 12 gives one bin per semitone
+    pub bins_per_octave: usize,
+    /// Requested bins, bins at or above Nyquist are dropped
+    pub bins: usize,
+    /// Samples between the centres of two consecutive frames
+    pub hop_size: usize,
+    pub scale: MagnitudeScale,
+}
+
+impl Default for CqtConfig {
+    /// Six octaves of semitones from A1 (55 Hz), which fits below Nyquist at 11025 Hz.
+    fn default() -> Self {
+        Self {
+            f_min: 55.0,
+            bins_per_octave: 12,
+            bins: 72,
+            hop_size: 512,
+            scale: MagnitudeScale::Linear,
+        }
+    }
+}
+
+impl CqtConfig {
+    /// Checks that frames can be cut and that the lowest bin lies below Nyquist at `sample_rate`.
+    pub fn validate(&self, sample_rate: u32) -> Result<(), SpectrogramConfigError> {
+        let nyquist = sample_rate as f32 / 2.0;
+        if self.hop_size == 0 {
+            return Err(SpectrogramConfigError::ZeroHop);
+        }
+        if self.bins_per_octave == 0 {
+            return Err(SpectrogramConfigError::ZeroBinsPerOctave);
+        }
+        if self.bins == 0 {
+            return Err(SpectrogramConfigError::ZeroBands);
+        }
+        if self.f_min <= 0.0 || self.f_min >= nyquist {
+            return Err(SpectrogramConfigError::MinFrequencyOutOfRange { f_min: self.f_min, nyquist });
+        }
+        Ok(())
+    }
+
+    /// Constant ratio between a bin's centre frequency and its bandwidth.
+    fn q(&self) -> f32 {
+        1.0 / (2f32.powf(1.0 / self.bins_per_octave as f32) - 1.0)
+    }
+
+    fn centre_hz(&self, bin: usize) -> f32 {
+        self.f_min * 2f32.powf(bin as f32 / self.bins_per_octave as f32)
+    }
+
+    /// Bins per frame at `sample_rate`.
+    pub fn bins(&self, sample_rate: u32) -> usize {
+        let nyquist = sample_rate as f32 / 2.0;
+        (0..self.bins).take_while(|&k| self.centre_hz(k) < nyquist).count()
+    }
+
+    /// Samples in the kernel of the lowest bin, the span every frame looks at.
+    pub fn longest_kernel(&self, sample_rate: u32) -> usize {
+        (self.q() * sample_rate as f32 / self.f_min).ceil().max(1.0) as usize
+    }
+
+    /// Frames produced from `samples` input samples. Frame `i` is centred on
+    /// sample `i * hop_size`, like `PaddingMode::Centre` for the STFT.
+    pub fn frame_count(&self, samples: usize) -> usize {
+        if samples == 0 { 0 } else { samples / self.hop_size + 1 }
+    }
+
+    /// Hz and seconds of the bins and frames this configuration produces.
+    pub fn units(&self, sample_rate: u32) -> SpectrogramUnits {
+        SpectrogramUnits {
+            sample_rate,
+            hop_size: self.hop_size,
+            fft_size: self.longest_kernel(sample_rate),
+            frequency: FrequencyScale::LogFrequency {
+                bins_per_octave: self.bins_per_octave,
+                f_min: self.f_min,
+            },
+        }
+    }
+}
+
+/// One windowed complex exponential per bin, all centred on the same sample.
+struct CqtKernel {
+    kernels: Vec<Vec<Complex<f32>>>,
+    // Length of the segment every frame is computed from
+    span: usize,
+    scale: MagnitudeScale,
+}
+
+impl CqtKernel {
+    fn new(config: &CqtConfig, sample_rate: u32) -> Result<Self, SpectrogramConfigError> {
+        config.validate(sample_rate)?;
+        let q = config.q();
+        let kernels = (0..config.bins(sample_rate))
+            .map(|k| {
+                // Longer kernels for lower bins keep the bandwidth proportional to the frequency
+                let len = (q * sample_rate as f32 / config.centre_hz(k)).ceil().max(1.0) as usize;
+                let window = WindowType::Hann.coefficients(len);
+                window.iter()
+                    .enumerate()
+                    .map(|(n, w)| {
+                        let phase = -2.0 * std::f32::consts::PI * q * n as f32 / len as f32;
+                        Complex::new(phase.cos(), phase.sin()) * (w / len as f32)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self { kernels, span: config.longest_kernel(sample_rate), scale: config.scale })
+    }
+
+    fn bins(&self) -> usize {
+        self.kernels.len()
+    }
+
+    /// Magnitudes of one frame. `segment` is `span` samples long and centred on the frame.
+    fn magnitudes_into(&self, segment: &[f32], out: &mut [f32]) {
+        let centre = self.span / 2;
+        for (kernel, out) in self.kernels.iter().zip(out.iter_mut()) {
+            let start = centre - kernel.len() / 2;
+            let sum: Complex<f32> = segment[start..start + kernel.len()]
+                .iter()
+                .zip(kernel)
+                .map(|(s, k)| *k * *s)
+                .sum();
+            *out = self.scale.apply(sum.norm());
+        }
+    }
+}
+
+/// Constant-Q spectrogram of `samples`, laid out like `create_spectrogram` so it
+/// can go straight into `save_spectrogram_peaks`.
+pub fn create_cqt_spectrogram(samples: &[f32], sample_rate: u32, config: &CqtConfig) -> Result<Spectrogram, SpectrogramConfigError> {
+    let kernel = CqtKernel::new(config, sample_rate)?;
+    let half = (kernel.span / 2) as isize;
+    let mut spectrogram = Spectrogram::new(kernel.bins(), config.units(sample_rate), config.scale);
+
+    let mut segment = vec![0f32; kernel.span];
+    let mut magnitudes = vec![0f32; kernel.bins()];
+    for frame in 0..config.frame_count(samples.len()) {
+        // Zeros stand in for everything before the first and after the last sample
+        let start = (frame * config.hop_size) as isize - half;
+        for (j, value) in segment.iter_mut().enumerate() {
+            let idx = start + j as isize;
+            *value = if (0..samples.len() as isize).contains(&idx) { samples[idx as usize] } else { 0.0 };
+        }
+        kernel.magnitudes_into(&segment, &mut magnitudes);
+        spectrogram.push_frame(&magnitudes);
+    }
+    Ok(spectrogram)
+}
+
+/// Iterator adapter that turns a stream of sample blocks into constant-Q columns.
+///
+/// Yields the same frames as `create_cqt_spectrogram` while only buffering one kernel span.
+pub struct CqtStream<I: Iterator<Item = Vec<f32>>> {
+    input: I,
+    kernel: CqtKernel,
+    config: CqtConfig,
+    buffer: Vec<f32>,
+    // Start of the current span in `buffer`, the samples before it are spent
+    offset: usize,
+    // Input samples still to drop when the hop is longer than the buffer
+    skip: usize,
+    consumed: usize,
+    emitted: usize,
+    // Known once the input is exhausted
+    expected: Option<usize>,
+}
+
+impl<I: Iterator<Item = Vec<f32>>> CqtStream<I> {
+    pub fn new(input: I, sample_rate: u32, config: &CqtConfig) -> Result<Self, SpectrogramConfigError> {
+        let kernel = CqtKernel::new(config, sample_rate)?;
+        // Silence in front so the first frame is centred on the first sample
+        let buffer = vec![0f32; kernel.span / 2];
+        Ok(Self {
+            input,
+            kernel,
+            config: config.clone(),
+            buffer,
+            offset: 0,
+            skip: 0,
+            consumed: 0,
+            emitted: 0,
+            expected: None,
+        })
+    }
+
+    /// Samples from the start of the current span on.
+    fn live(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    /// Pulls one block, or records the final frame count once the input is exhausted.
+    fn pull(&mut self) {
+        match self.input.next() {
+            Some(block) => {
+                self.consumed += block.len();
+                let skipped = self.skip.min(block.len());
+                self.skip -= skipped;
+                // Drop the spent samples once they outweigh the live ones
+                if self.offset > 0 && self.offset >= self.live() {
+                    self.buffer.drain(..self.offset);
+                    self.offset = 0;
+                }
+                self.buffer.extend_from_slice(&block[skipped..]);
+            }
+            None => self.expected = Some(self.config.frame_count(self.consumed)),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> ColumnStream for CqtStream<I> {
+    fn bins(&self) -> usize {
+        self.kernel.bins()
+    }
+
+    fn next_into(&mut self, out: &mut [f32]) -> bool {
+        let span = self.kernel.span;
+        while self.live() < span && self.expected.is_none() {
+            self.pull();
+        }
+        if let Some(expected) = self.expected {
+            if self.emitted >= expected {
+                return false;
+            }
+            // Past the end of the input the kernels only see silence
+            if self.live() < span {
+                self.buffer.resize(self.offset + span, 0.0);
+            }
+        }
+
+        self.kernel.magnitudes_into(&self.buffer[self.offset..self.offset + span], out);
+        self.emitted += 1;
+
+        // Slide the centre forward by one hop
+        let hop = self.config.hop_size;
+        if hop <= self.live() {
+            self.offset += hop;
+        } else {
+            self.skip = hop - self.live();
+            self.buffer.clear();
+            self.offset = 0;
+        }
+        true
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> Iterator for CqtStream<I> {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        let mut magnitudes = vec![0f32; self.kernel.bins()];
+        self.next_into(&mut magnitudes).then_some(magnitudes)
+    }
+}
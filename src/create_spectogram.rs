@@ -1,37 +1,909 @@
-use rustfft::{FftPlanner, num_complex::Complex};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use rayon::prelude::*;
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use serde::{Serialize, Deserialize};
 
-const WINDOW_SIZE: usize = 1024;
-const OVERLAP: usize = WINDOW_SIZE/2;
+/// Tapering applied to each frame before the FFT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WindowType {
+    #[default]
+    Hann,
+    Hamming,
+    BlackmanHarris,
+    Rectangular,
+}
+
+impl WindowType {
+    pub fn coefficients(self, len: usize) -> Vec<f32> {
+        let n = len as f32 - 1.0;
+        let phase = |i: usize, k: f32| (k * 2.0 * std::f32::consts::PI * i as f32 / n).cos();
+
+        (0..len)
+            .map(|i| match self {
+                WindowType::Hann => 0.5 * (1.0 - phase(i, 1.0)),
+                WindowType::Hamming => 0.54 - 0.46 * phase(i, 1.0),
+                WindowType::BlackmanHarris => {
+                    0.35875 - 0.48829 * phase(i, 1.0) + 0.14128 * phase(i, 2.0) - 0.01168 * phase(i, 3.0)
+                }
+                WindowType::Rectangular => 1.0,
+            })
+            .collect()
+    }
+}
+
+/// FFT implementation used for each frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FftBackend {
+    /// Complex FFT on real input, bit-for-bit identical to the spectrograms
+    /// existing databases were built from
+    #[default]
+    Reference,
+    /// Real-to-complex FFT, roughly twice as fast but not bit-exact with `Reference`.
+    /// Needs the `realfft` crate (3.x, the line built on rustfft 6) next to `rustfft`
+    Real,
+}
+
+/// How FFT magnitudes are scaled before they are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MagnitudeScale {
+    /// |X|, what the peak finder was originally tuned on
+    #[default]
+    Linear,
+    /// |X|^2
+    Power,
+    /// 20 * log10(|X|), clamped from below at `floor_db`
+    Decibel { floor_db: f32 },
+    /// ln(1 + |X|), compresses loud bass without going negative
+    Log1p,
+}
+
+impl MagnitudeScale {
+    pub(crate) fn apply(self, magnitude: f32) -> f32 {
+        match self {
+            MagnitudeScale::Linear => magnitude,
+            MagnitudeScale::Power => magnitude * magnitude,
+            MagnitudeScale::Decibel { floor_db } => (20.0 * magnitude.max(1e-10).log10()).max(floor_db),
+            MagnitudeScale::Log1p => magnitude.ln_1p(),
+        }
+    }
+
+    /// Whether values are already logarithmic and can be displayed as they are.
+    pub fn is_logarithmic(self) -> bool {
+        matches!(self, MagnitudeScale::Decibel { .. } | MagnitudeScale::Log1p)
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
 
+/// Spacing of the frequency axis. Warped scales fold the FFT bins into
+/// triangular bands, so bin `k` of the spectrogram is band `k`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FrequencyScale {
+    /// One bin per FFT bin
+    #[default]
+    Linear,
+    /// `bands` mel bands between `f_min` and `f_max` (Nyquist when `None`)
+    Mel { bands: usize, f_min: f32, f_max: Option<f32> },
+    /// Logarithmically spaced bands from `f_min` up to Nyquist
+    LogFrequency { bins_per_octave: usize, f_min: f32 },
+}
 
-pub fn create_spectrogram(samples: &[f32]) -> Vec<Vec<f32>> {
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(WINDOW_SIZE);
+impl FrequencyScale {
+    /// Checks that a warped scale has bands and starts between 0 Hz and Nyquist.
+    pub fn validate(self, nyquist: f32) -> Result<(), SpectrogramConfigError> {
+        self.band_count(nyquist).map(|_| ())
+    }
 
-    // Hann Window function to reduce spectral leakage
-    let window: Vec<f32> = (0..WINDOW_SIZE)
-        .map(|i| 0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / (WINDOW_SIZE as f32 - 1.0)).cos()))
-        .collect();
+    /// Number of bands a warped scale produces, `None` for the linear scale.
+    fn band_count(self, nyquist: f32) -> Result<Option<usize>, SpectrogramConfigError> {
+        let bands = match self {
+            FrequencyScale::Linear => return Ok(None),
+            FrequencyScale::Mel { bands, f_min, f_max } => {
+                // Mel bands may start at DC
+                if !(0.0..nyquist).contains(&f_min) {
+                    return Err(SpectrogramConfigError::MinFrequencyOutOfRange { f_min, nyquist });
+                }
+                if let Some(f_max) = f_max.filter(|&f_max| f_max <= f_min) {
+                    return Err(SpectrogramConfigError::EmptyFrequencyRange { f_min, f_max });
+                }
+                bands
+            }
+            FrequencyScale::LogFrequency { bins_per_octave, f_min } => {
+                if bins_per_octave == 0 {
+                    return Err(SpectrogramConfigError::ZeroBinsPerOctave);
+                }
+                if f_min <= 0.0 || f_min >= nyquist {
+                    return Err(SpectrogramConfigError::MinFrequencyOutOfRange { f_min, nyquist });
+                }
+                (bins_per_octave as f32 * (nyquist / f_min).log2()).floor() as usize
+            }
+        };
+        if bands == 0 { Err(SpectrogramConfigError::ZeroBands) } else { Ok(Some(bands)) }
+    }
 
-    let mut spectrogram = Vec::new();
+    /// Centre of a (possibly fractional) band in Hz. Only meaningful for warped scales.
+    fn band_to_hz(self, band: f32, nyquist: f32) -> f32 {
+        match self {
+            FrequencyScale::Linear => band,
+            FrequencyScale::Mel { bands, f_min, f_max } => {
+                let (mel_min, mel_max) = (hz_to_mel(f_min), hz_to_mel(f_max.unwrap_or(nyquist)));
+                let step = (mel_max - mel_min) / (bands as f32 + 1.0);
+                mel_to_hz(mel_min + (band + 1.0) * step)
+            }
+            FrequencyScale::LogFrequency { bins_per_octave, f_min } => {
+                f_min * 2f32.powf(band / bins_per_octave as f32)
+            }
+        }
+    }
 
-    // Sliding window
-    for chunk in samples.windows(WINDOW_SIZE).step_by(WINDOW_SIZE - OVERLAP) {
-        let mut buffer: Vec<Complex<f32>> = chunk.iter()
-            .zip(&window)
-            .map(|(&s, &w)| Complex::new(s * w, 0.0))
+    fn hz_to_band(self, hz: f32, nyquist: f32) -> f32 {
+        match self {
+            FrequencyScale::Linear => hz,
+            FrequencyScale::Mel { bands, f_min, f_max } => {
+                let (mel_min, mel_max) = (hz_to_mel(f_min), hz_to_mel(f_max.unwrap_or(nyquist)));
+                let step = (mel_max - mel_min) / (bands as f32 + 1.0);
+                (hz_to_mel(hz) - mel_min) / step - 1.0
+            }
+            FrequencyScale::LogFrequency { bins_per_octave, f_min } => {
+                bins_per_octave as f32 * (hz / f_min).log2()
+            }
+        }
+    }
+}
+
+/// Triangular filters mapping FFT bins onto warped bands.
+struct FilterBank {
+    // First FFT bin and weights of each band
+    filters: Vec<(usize, Vec<f32>)>,
+}
+
+impl FilterBank {
+    fn new(scale: FrequencyScale, sample_rate: u32, fft_size: usize) -> Result<Option<Self>, SpectrogramConfigError> {
+        let nyquist = sample_rate as f32 / 2.0;
+        let bands = match scale.band_count(nyquist)? {
+            Some(bands) => bands,
+            None => return Ok(None),
+        };
+        let fft_bins = fft_size / 2;
+        let bin_hz = sample_rate as f32 / fft_size as f32;
+
+        let filters = (0..bands)
+            .map(|band| {
+                let lo = scale.band_to_hz(band as f32 - 1.0, nyquist);
+                let centre = scale.band_to_hz(band as f32, nyquist);
+                let hi = scale.band_to_hz(band as f32 + 1.0, nyquist);
+
+                let weights: Vec<(usize, f32)> = (0..fft_bins)
+                    .filter_map(|k| {
+                        let f = k as f32 * bin_hz;
+                        let w = if f > lo && f <= centre {
+                            (f - lo) / (centre - lo)
+                        } else if f > centre && f < hi {
+                            (hi - f) / (hi - centre)
+                        } else {
+                            0.0
+                        };
+                        (w > 0.0).then_some((k, w))
+                    })
+                    .collect();
+
+                match (weights.first(), weights.last()) {
+                    (Some(&(first, _)), Some(&(last, _))) => {
+                        let mut dense = vec![0.0; last - first + 1];
+                        for (k, w) in weights {
+                            dense[k - first] = w;
+                        }
+                        (first, dense)
+                    }
+                    // Bands narrower than one FFT bin take the nearest bin
+                    _ => (((centre / bin_hz).round() as usize).min(fft_bins.saturating_sub(1)), vec![1.0]),
+                }
+            })
             .collect();
 
-        fft.process(&mut buffer);
+        Ok(Some(Self { filters }))
+    }
+
+    fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    fn apply(&self, linear: &[f32], out: &mut [f32]) {
+        for (slot, (first, weights)) in out.iter_mut().zip(&self.filters) {
+            *slot = linear[*first..*first + weights.len()]
+                .iter()
+                .zip(weights)
+                .map(|(m, w)| m * w)
+                .sum();
+        }
+    }
+}
+
+/// How the signal is extended so its edges end up in a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaddingMode {
+    /// Only whole windows, the trailing partial window is dropped and inputs
+    /// shorter than one window produce no frames
+    #[default]
+    None,
+    /// Zeros after the last sample so the tail gets its own frame
+    ZeroTail,
+    /// Half a window of zeros on both ends, frame `i` is centred on sample `i * hop`
+    Centre,
+    /// Like `Centre`, but the padding mirrors the signal instead of being silent
+    Reflect,
+}
+
+impl PaddingMode {
+    /// Samples added in front of the signal.
+    fn left_pad(self, window_size: usize) -> usize {
+        match self {
+            PaddingMode::Centre | PaddingMode::Reflect => window_size / 2,
+            PaddingMode::None | PaddingMode::ZeroTail => 0,
+        }
+    }
+}
+
+/// Sample `idx` of `signal` extended by the padding mode, `idx` may fall outside the signal.
+fn padded_sample(signal: &[f32], idx: isize, mode: PaddingMode) -> f32 {
+    let len = signal.len() as isize;
+    if (0..len).contains(&idx) {
+        return signal[idx as usize];
+    }
+    if mode != PaddingMode::Reflect || len < 2 {
+        return 0.0;
+    }
+
+    // Mirror around the edge samples without repeating them, folding until we land inside
+    let period = 2 * (len - 1);
+    let folded = idx.rem_euclid(period);
+    let mirrored = if folded < len { folded } else { period - folded };
+    signal[mirrored as usize]
+}
+
+/// Settings that can't produce a spectrogram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpectrogramConfigError {
+    ZeroHop,
+    WindowTooShort { window_size: usize },
+    HopLongerThanWindow { hop_size: usize, window_size: usize },
+    ZeroBands,
+    ZeroBinsPerOctave,
+    MinFrequencyOutOfRange { f_min: f32, nyquist: f32 },
+    EmptyFrequencyRange { f_min: f32, f_max: f32 },
+}
+
+impl fmt::Display for SpectrogramConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpectrogramConfigError::ZeroHop => write!(f, "hop size must be at least 1"),
+            SpectrogramConfigError::WindowTooShort { window_size } => {
+                write!(f, "window size is {}, it must be at least 2", window_size)
+            }
+            SpectrogramConfigError::HopLongerThanWindow { hop_size, window_size } => {
+                write!(f, "hop size {} is longer than the window size {}", hop_size, window_size)
+            }
+            SpectrogramConfigError::ZeroBands => write!(f, "frequency scale has no bands"),
+            SpectrogramConfigError::ZeroBinsPerOctave => write!(f, "bins per octave must be at least 1"),
+            SpectrogramConfigError::MinFrequencyOutOfRange { f_min, nyquist } => {
+                write!(f, "lowest frequency {} Hz is outside 0 Hz to Nyquist ({} Hz)", f_min, nyquist)
+            }
+            SpectrogramConfigError::EmptyFrequencyRange { f_min, f_max } => {
+                write!(f, "highest frequency {} Hz is not above the lowest frequency {} Hz", f_max, f_min)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SpectrogramConfigError {}
+
+/// Frame layout of the STFT.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpectrogramConfig {
+    /// Samples per FFT frame
+    pub window_size: usize,
+    /// Samples between the starts of two consecutive frames
+    pub hop_size: usize,
+    pub window: WindowType,
+    pub fft: FftBackend,
+    /// Split the frames of a single `create_spectrogram` call across rayon
+    pub parallel: bool,
+    pub scale: MagnitudeScale,
+    pub frequency: FrequencyScale,
+    pub padding: PaddingMode,
+}
+
+impl Default for SpectrogramConfig {
+    /// 1024 sample Hann window with 50% overlap.
+    fn default() -> Self {
+        Self {
+            window_size: 1024,
+            hop_size: 512,
+            window: WindowType::Hann,
+            fft: FftBackend::Reference,
+            parallel: false,
+            scale: MagnitudeScale::Linear,
+            frequency: FrequencyScale::Linear,
+            padding: PaddingMode::None,
+        }
+    }
+}
+
+impl SpectrogramConfig {
+    /// Checks that frames can be cut and that no sample falls between two frames.
+    pub fn validate(&self) -> Result<(), SpectrogramConfigError> {
+        if self.hop_size == 0 {
+            return Err(SpectrogramConfigError::ZeroHop);
+        }
+        if self.window_size < 2 {
+            return Err(SpectrogramConfigError::WindowTooShort { window_size: self.window_size });
+        }
+        if self.hop_size > self.window_size {
+            return Err(SpectrogramConfigError::HopLongerThanWindow {
+                hop_size: self.hop_size,
+                window_size: self.window_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// FFT bins per frame, up to (excluding) Nyquist.
+    pub fn fft_bins(&self) -> usize {
+        self.window_size / 2
+    }
+
+    /// Bins per spectrogram frame at `sample_rate`, after frequency warping.
+    /// Fails like `FrequencyScale::validate` when the scale doesn't fit the sample rate.
+    pub fn bins(&self, sample_rate: u32) -> Result<usize, SpectrogramConfigError> {
+        let bands = self.frequency.band_count(sample_rate as f32 / 2.0)?;
+        Ok(bands.unwrap_or_else(|| self.fft_bins()))
+    }
+
+    /// Frames produced from `samples` input samples, defined for any length.
+    pub fn frame_count(&self, samples: usize) -> usize {
+        match self.padding {
+            PaddingMode::None if samples < self.window_size => 0,
+            PaddingMode::None => (samples - self.window_size) / self.hop_size + 1,
+            _ if samples == 0 => 0,
+            PaddingMode::ZeroTail if samples <= self.window_size => 1,
+            PaddingMode::ZeroTail => (samples - self.window_size).div_ceil(self.hop_size) + 1,
+            PaddingMode::Centre | PaddingMode::Reflect => samples / self.hop_size + 1,
+        }
+    }
+
+    /// Length of the padded signal that exactly covers `frames` frames.
+    fn covered_len(&self, frames: usize) -> usize {
+        if frames == 0 { 0 } else { (frames - 1) * self.hop_size + self.window_size }
+    }
+}
+
+/// What a bin and a frame index mean in Hz and seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpectrogramUnits {
+    /// Rate of the samples fed to the FFT
+    pub sample_rate: u32,
+    pub hop_size: usize,
+    pub fft_size: usize,
+    #[serde(default)]
+    pub frequency: FrequencyScale,
+}
+
+impl Default for SpectrogramUnits {
+    /// The default pipeline: 11025 Hz with the default `SpectrogramConfig`.
+    fn default() -> Self {
+        SpectrogramUnits::new(11025, &SpectrogramConfig::default())
+    }
+}
+
+impl SpectrogramUnits {
+    pub fn new(sample_rate: u32, config: &SpectrogramConfig) -> Self {
+        Self {
+            sample_rate,
+            hop_size: config.hop_size,
+            fft_size: config.window_size,
+            frequency: config.frequency,
+        }
+    }
+
+    /// Centre frequency of a (possibly fractional) bin.
+    pub fn bin_to_hz(&self, bin: f32) -> f32 {
+        match self.frequency {
+            FrequencyScale::Linear => bin * self.sample_rate as f32 / self.fft_size as f32,
+            warped => warped.band_to_hz(bin, self.sample_rate as f32 / 2.0),
+        }
+    }
+
+    /// Fractional bin of a frequency, round it to index a column.
+    pub fn hz_to_bin(&self, hz: f32) -> f32 {
+        match self.frequency {
+            FrequencyScale::Linear => hz * self.fft_size as f32 / self.sample_rate as f32,
+            warped => warped.hz_to_band(hz, self.sample_rate as f32 / 2.0),
+        }
+    }
+
+    /// Start time of a (possibly fractional) frame.
+    pub fn frame_to_seconds(&self, frame: f32) -> f32 {
+        frame * self.hop_size as f32 / self.sample_rate as f32
+    }
+
+    pub fn seconds_to_frame(&self, seconds: f32) -> f32 {
+        seconds * self.sample_rate as f32 / self.hop_size as f32
+    }
+}
+
+/// Magnitudes stored frame after frame in one buffer, with the units needed to read them.
+#[derive(Clone, Debug)]
+pub struct Spectrogram {
+    data: Vec<f32>,
+    bins: usize,
+    pub units: SpectrogramUnits,
+    /// Scaling applied to the stored values
+    pub scale: MagnitudeScale,
+}
+
+impl Spectrogram {
+    pub fn new(bins: usize, units: SpectrogramUnits, scale: MagnitudeScale) -> Self {
+        Self { data: Vec::new(), bins, units, scale }
+    }
+
+    /// Wraps row-major magnitudes, `data.len()` must be a multiple of `bins`.
+    pub fn from_data(data: Vec<f32>, bins: usize, units: SpectrogramUnits, scale: MagnitudeScale) -> Self {
+        assert!(bins > 0 || data.is_empty(), "non-empty spectrogram needs at least one bin");
+        assert_eq!(data.len() % bins.max(1), 0, "data is not a whole number of frames");
+        Self { data, bins, units, scale }
+    }
+
+    /// Appends one frame, which must hold exactly `bins` magnitudes.
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        assert_eq!(frame.len(), self.bins, "frame has the wrong number of bins");
+        self.data.extend_from_slice(frame);
+    }
+
+    pub fn bins(&self) -> usize {
+        self.bins
+    }
+
+    pub fn frames(&self) -> usize {
+        self.data.len().checked_div(self.bins).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn frame(&self, index: usize) -> &[f32] {
+        &self.data[index * self.bins..(index + 1) * self.bins]
+    }
+
+    /// Row-major magnitudes, `frames() * bins()` long.
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    pub fn iter(&self) -> std::slice::ChunksExact<'_, f32> {
+        self.data.chunks_exact(self.bins.max(1))
+    }
+
+    pub fn bin_to_hz(&self, bin: f32) -> f32 {
+        self.units.bin_to_hz(bin)
+    }
+
+    pub fn hz_to_bin(&self, hz: f32) -> f32 {
+        self.units.hz_to_bin(hz)
+    }
+
+    pub fn frame_to_seconds(&self, frame: f32) -> f32 {
+        self.units.frame_to_seconds(frame)
+    }
+
+    pub fn seconds_to_frame(&self, seconds: f32) -> f32 {
+        self.units.seconds_to_frame(seconds)
+    }
+}
+
+impl<'a> IntoIterator for &'a Spectrogram {
+    type Item = &'a [f32];
+    type IntoIter = std::slice::ChunksExact<'a, f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+enum Plan {
+    Complex(Arc<dyn Fft<f32>>),
+    Real(Arc<dyn RealToComplex<f32>>),
+}
+
+/// FFT plan and window shared by the batch and streaming spectrograms.
+/// Read-only, so one analyzer can serve several threads, each with its own `FrameScratch`.
+struct FrameAnalyzer {
+    plan: Plan,
+    window: Vec<f32>,
+    fft_bins: usize,
+    filters: Option<FilterBank>,
+    scale: MagnitudeScale,
+}
+
+/// Buffers reused from frame to frame so the FFT loop doesn't allocate.
+struct FrameScratch {
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    // Linear magnitudes before warping, only used with a filter bank
+    linear: Vec<f32>,
+}
+
+impl FrameAnalyzer {
+    fn new(config: &SpectrogramConfig, sample_rate: u32) -> Result<Self, SpectrogramConfigError> {
+        config.validate()?;
+        let plan = match config.fft {
+            FftBackend::Reference => {
+                let mut planner = FftPlanner::new();
+                Plan::Complex(planner.plan_fft_forward(config.window_size))
+            }
+            FftBackend::Real => {
+                let mut planner = RealFftPlanner::new();
+                Plan::Real(planner.plan_fft_forward(config.window_size))
+            }
+        };
+
+        // Window function to reduce spectral leakage
+        let window = config.window.coefficients(config.window_size);
+
+        Ok(Self {
+            plan,
+            window,
+            fft_bins: config.fft_bins(),
+            filters: FilterBank::new(config.frequency, sample_rate, config.window_size)?,
+            scale: config.scale,
+        })
+    }
+
+    /// Values per output frame.
+    fn bins(&self) -> usize {
+        self.filters.as_ref().map(|f| f.len()).unwrap_or(self.fft_bins)
+    }
+
+    fn scratch(&self) -> FrameScratch {
+        let zero = Complex::new(0.0, 0.0);
+        let linear = if self.filters.is_some() { vec![0.0; self.fft_bins] } else { Vec::new() };
+        match &self.plan {
+            Plan::Complex(fft) => FrameScratch {
+                input: Vec::new(),
+                spectrum: vec![zero; self.window.len()],
+                scratch: vec![zero; fft.get_inplace_scratch_len()],
+                linear,
+            },
+            Plan::Real(fft) => FrameScratch {
+                input: fft.make_input_vec(),
+                spectrum: fft.make_output_vec(),
+                scratch: fft.make_scratch_vec(),
+                linear,
+            },
+        }
+    }
+
+    /// Writes the values of one windowed frame into `out` (`bins()` long).
+    fn magnitudes_into(&self, chunk: &[f32], scratch: &mut FrameScratch, out: &mut [f32]) {
+        match &self.plan {
+            Plan::Complex(fft) => {
+                for ((slot, &s), &w) in scratch.spectrum.iter_mut().zip(chunk).zip(&self.window) {
+                    *slot = Complex::new(s * w, 0.0);
+                }
+                fft.process_with_scratch(&mut scratch.spectrum, &mut scratch.scratch);
+            }
+            Plan::Real(fft) => {
+                for ((slot, &s), &w) in scratch.input.iter_mut().zip(chunk).zip(&self.window) {
+                    *slot = s * w;
+                }
+                fft.process_with_scratch(&mut scratch.input, &mut scratch.spectrum, &mut scratch.scratch)
+                    .expect("buffers are sized by the plan");
+            }
+        }
 
         // Calculate magnitude for the first half (Nyquist limit)
-        let magnitudes: Vec<f32> = buffer.iter()
-            .take(WINDOW_SIZE/2)
-            .map(|c| c.norm())
+        let magnitudes = &scratch.spectrum[..self.fft_bins];
+        match &self.filters {
+            Some(filters) => {
+                for (slot, c) in scratch.linear.iter_mut().zip(magnitudes) {
+                    *slot = c.norm();
+                }
+                filters.apply(&scratch.linear, out);
+            }
+            None => {
+                for (slot, c) in out.iter_mut().zip(magnitudes) {
+                    *slot = c.norm();
+                }
+            }
+        }
+
+        if self.scale != MagnitudeScale::Linear {
+            for slot in out.iter_mut() {
+                *slot = self.scale.apply(*slot);
+            }
+        }
+    }
+}
+
+pub fn create_spectrogram(samples: &[f32], sample_rate: u32, config: &SpectrogramConfig) -> Result<Spectrogram, SpectrogramConfigError> {
+    let analyzer = FrameAnalyzer::new(config, sample_rate)?;
+    let bins = analyzer.bins();
+    let frames = config.frame_count(samples.len());
+    let mut data = vec![0f32; frames * bins];
+
+    let padded: Cow<[f32]> = if config.padding == PaddingMode::None {
+        Cow::Borrowed(samples)
+    } else {
+        let left = config.padding.left_pad(config.window_size) as isize;
+        Cow::Owned((0..config.covered_len(frames) as isize)
+            .map(|p| padded_sample(samples, p - left, config.padding))
+            .collect())
+    };
+    let samples: &[f32] = &padded;
+
+    // Sliding window, frame i covers samples[i * hop .. i * hop + window]
+    let compute = |scratch: &mut FrameScratch, (i, out): (usize, &mut [f32])| {
+        let start = i * config.hop_size;
+        analyzer.magnitudes_into(&samples[start..start + config.window_size], scratch, out);
+    };
+
+    if config.parallel {
+        data.par_chunks_mut(bins.max(1))
+            .enumerate()
+            .for_each_init(|| analyzer.scratch(), compute);
+    } else {
+        let mut scratch = analyzer.scratch();
+        data.chunks_mut(bins.max(1))
+            .enumerate()
+            .for_each(|item| compute(&mut scratch, item));
+    }
+
+    Ok(Spectrogram::from_data(data, bins, SpectrogramUnits::new(sample_rate, config), config.scale))
+}
+
+/// Source of spectrogram columns that writes each one into a buffer owned by the caller.
+///
+/// The streams also implement `Iterator`, which allocates a fresh column per frame.
+/// The indexing loop goes through this trait instead and reuses one column.
+pub trait ColumnStream {
+    /// Values per column.
+    fn bins(&self) -> usize;
+
+    /// Writes the next column into `out` (`bins()` long), `false` once the input is exhausted.
+    fn next_into(&mut self, out: &mut [f32]) -> bool;
+}
+
+/// Iterator adapter that turns a stream of sample blocks into spectrogram columns.
+///
+/// Yields the same frames as `create_spectrogram` while only buffering one window.
+pub struct SpectrogramStream<I: Iterator<Item = Vec<f32>>> {
+    input: I,
+    analyzer: FrameAnalyzer,
+    scratch: FrameScratch,
+    config: SpectrogramConfig,
+    buffer: Vec<f32>,
+    // Start of the current window in `buffer`, the samples before it are spent
+    offset: usize,
+    started: bool,
+    // Total samples read from `input`
+    consumed: usize,
+    emitted: usize,
+    // Known once the input is exhausted
+    expected: Option<usize>,
+    tail_padded: bool,
+}
+
+impl<I: Iterator<Item = Vec<f32>>> SpectrogramStream<I> {
+    pub fn new(input: I, sample_rate: u32, config: &SpectrogramConfig) -> Result<Self, SpectrogramConfigError> {
+        let analyzer = FrameAnalyzer::new(config, sample_rate)?;
+        Ok(Self {
+            input,
+            scratch: analyzer.scratch(),
+            analyzer,
+            config: config.clone(),
+            buffer: Vec::with_capacity(2 * config.window_size),
+            offset: 0,
+            started: false,
+            consumed: 0,
+            emitted: 0,
+            expected: None,
+            tail_padded: false,
+        })
+    }
+
+    /// Samples from the start of the current window on.
+    fn live(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    /// Pulls one block, or records the final frame count once the input is exhausted.
+    fn pull(&mut self) {
+        match self.input.next() {
+            Some(block) => {
+                self.consumed += block.len();
+                // Drop the spent samples once they outweigh the live ones, so every
+                // sample is moved a bounded number of times
+                if self.offset > 0 && self.offset >= self.live() {
+                    self.buffer.drain(..self.offset);
+                    self.offset = 0;
+                }
+                self.buffer.extend_from_slice(&block);
+            }
+            None => self.expected = Some(self.config.frame_count(self.consumed)),
+        }
+    }
+
+    /// Prepends the leading padding, reflection needs the first samples of the signal.
+    fn pad_front(&mut self) {
+        let left = self.config.padding.left_pad(self.config.window_size);
+        while self.expected.is_none() && self.buffer.len() <= left {
+            self.pull();
+        }
+
+        let mut padded: Vec<f32> = (0..left as isize)
+            .map(|j| padded_sample(&self.buffer, j - left as isize, self.config.padding))
             .collect();
+        padded.extend_from_slice(&self.buffer);
+        self.buffer = padded;
+    }
+
+    /// Appends the trailing padding for every frame still owed once the input is done.
+    fn pad_tail(&mut self, expected: usize) {
+        let needed = self.config.covered_len(expected - self.emitted);
+        let (start, real) = (self.offset, self.live());
+        for t in real..needed {
+            let value = padded_sample(&self.buffer[start..start + real], t as isize, self.config.padding);
+            self.buffer.push(value);
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> ColumnStream for SpectrogramStream<I> {
+    fn bins(&self) -> usize {
+        self.analyzer.bins()
+    }
+
+    fn next_into(&mut self, out: &mut [f32]) -> bool {
+        if !self.started {
+            self.started = true;
+            self.pad_front();
+        }
 
-        spectrogram.push(magnitudes);
+        let window_size = self.config.window_size;
+        while self.live() < window_size && self.expected.is_none() {
+            self.pull();
+        }
+        if let Some(expected) = self.expected {
+            if self.emitted >= expected {
+                return false;
+            }
+            if !self.tail_padded {
+                self.tail_padded = true;
+                self.pad_tail(expected);
+            }
+        }
+        if self.live() < window_size {
+            return false;
+        }
+
+        let window = &self.buffer[self.offset..self.offset + window_size];
+        self.analyzer.magnitudes_into(window, &mut self.scratch, out);
+        self.emitted += 1;
+
+        // Slide the window forward by one hop
+        self.offset += self.config.hop_size.min(self.live());
+        true
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> Iterator for SpectrogramStream<I> {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        let mut magnitudes = vec![0f32; ColumnStream::bins(self)];
+        self.next_into(&mut magnitudes).then_some(magnitudes)
     }
-    spectrogram
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The per-frame complex FFT loop the crate started with, kept as the reference.
+    fn baseline_spectrogram(samples: &[f32]) -> Vec<Vec<f32>> {
+        const WINDOW_SIZE: usize = 1024;
+        const OVERLAP: usize = WINDOW_SIZE / 2;
+
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(WINDOW_SIZE);
+        let window: Vec<f32> = (0..WINDOW_SIZE)
+            .map(|i| 0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / (WINDOW_SIZE as f32 - 1.0)).cos()))
+            .collect();
+
+        let mut spectrogram = Vec::new();
+        for chunk in samples.windows(WINDOW_SIZE).step_by(WINDOW_SIZE - OVERLAP) {
+            let mut buffer: Vec<Complex<f32>> = chunk.iter()
+                .zip(&window)
+                .map(|(&s, &w)| Complex::new(s * w, 0.0))
+                .collect();
+            fft.process(&mut buffer);
+            spectrogram.push(buffer.iter().take(WINDOW_SIZE / 2).map(|c| c.norm()).collect());
+        }
+        spectrogram
+    }
+
+    /// Two tones and a little deterministic noise, long enough for a partial last hop.
+    fn signal() -> Vec<f32> {
+        let mut seed = 0x2545_f491u32;
+        (0..2 * 11025 + 333)
+            .map(|i| {
+                let t = i as f32 / 11025.0;
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                0.6 * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+                    + 0.3 * (2.0 * std::f32::consts::PI * 1234.5 * t).sin()
+                    + 0.05 * noise
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reference_backend_matches_baseline_bit_for_bit() {
+        let samples = signal();
+        let expected = baseline_spectrogram(&samples);
+        let spectrogram = create_spectrogram(&samples, 11025, &SpectrogramConfig::default()).unwrap();
+
+        assert_eq!(spectrogram.frames(), expected.len());
+        for (frame, expected) in spectrogram.iter().zip(&expected) {
+            let bits: Vec<u32> = frame.iter().map(|m| m.to_bits()).collect();
+            let expected_bits: Vec<u32> = expected.iter().map(|m| m.to_bits()).collect();
+            assert_eq!(bits, expected_bits);
+        }
+    }
+
+    #[test]
+    fn real_backend_stays_close_to_reference() {
+        let samples = signal();
+        let reference = create_spectrogram(&samples, 11025, &SpectrogramConfig::default()).unwrap();
+        let config = SpectrogramConfig { fft: FftBackend::Real, ..SpectrogramConfig::default() };
+        let real = create_spectrogram(&samples, 11025, &config).unwrap();
+
+        assert_eq!(real.frames(), reference.frames());
+        assert_eq!(real.bins(), reference.bins());
+        let peak = reference.data().iter().fold(0f32, |a, &b| a.max(b));
+        for (r, c) in real.data().iter().zip(reference.data()) {
+            assert!((r - c).abs() <= 1e-4 * peak, "real {} vs reference {}", r, c);
+        }
+    }
+
+    #[test]
+    fn stream_matches_batch_for_every_padding() {
+        let samples = signal();
+        let paddings = [PaddingMode::None, PaddingMode::ZeroTail, PaddingMode::Centre, PaddingMode::Reflect];
+        for padding in paddings {
+            let config = SpectrogramConfig { hop_size: 384, padding, ..SpectrogramConfig::default() };
+            let batch = create_spectrogram(&samples, 11025, &config).unwrap();
+
+            // Odd block sizes so windows straddle blocks and the buffer gets compacted
+            let blocks = samples.chunks(1000).map(<[f32]>::to_vec);
+            let mut stream = SpectrogramStream::new(blocks, 11025, &config).unwrap();
+            let mut column = vec![0f32; stream.bins()];
+            let mut frames = 0;
+            while stream.next_into(&mut column) {
+                assert_eq!(column.as_slice(), batch.frame(frames), "{:?} frame {}", padding, frames);
+                frames += 1;
+            }
+            assert_eq!(frames, batch.frames(), "{:?}", padding);
+        }
+    }
+}
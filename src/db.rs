@@ -2,32 +2,159 @@ use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use crate::pipeline::extract_features;
-use crate::types::types::Fingerprint;
+use crate::create_spectogram::SpectrogramUnits;
+use crate::generate_fingerprints::{Fingerprinter, PairFingerprinter};
+use crate::load_audio_mono::{AudioLoadError, AudioMetadata, LoadOptions, supported_extensions};
+use crate::pipeline::{extract_peaks_per_channel, extract_peaks_with_config, extract_peaks_with_metadata, PipelineConfig};
+use crate::types::types::{Fingerprint, SpectrogramPoint};
 
 
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use std::io::{BufReader, BufWriter};
 use std::sync::mpsc;
 use rayon::prelude::*;
+
+// If we only have 3 or 4 random hashes align, it could be a coincidence.
+// 10+ aligned hashes is statistically impossible to happen by chance.
+const MATCH_THRESHOLD: f32 = 10.0;
+
+/// Best match of a query that may be played faster or slower than the indexed song.
+#[derive(Clone, Debug)]
+pub struct StretchMatch {
+    pub song: String,
+    /// Song frames per query frame, 1.03 for a query played 3% faster
+    pub stretch: f32,
+    /// Frame of the song where the query starts
+    pub offset: i64,
+    /// Hashes agreeing with this stretch and offset, fuzzy hits weighted down
+    pub aligned: f32,
+}
+
+/// Weighted least-squares line `song_time = stretch * query_time + offset` through the
+/// hits within one frame of the line found by the grid search.
+fn fit_stretch(pairs: &[(usize, usize, f32)], stretch: f32, offset: i64) -> Option<(f32, i64)> {
+    let aligned = pairs.iter().filter(|&&(query_time, db_time, _)| {
+        (db_time as f32 - stretch * query_time as f32 - offset as f32).abs() <= 1.0
+    });
+
+    // 1. Weighted means
+    let (mut sum_w, mut sum_q, mut sum_d) = (0.0f64, 0.0f64, 0.0f64);
+    for &(query_time, db_time, weight) in aligned.clone() {
+        sum_w += weight as f64;
+        sum_q += weight as f64 * query_time as f64;
+        sum_d += weight as f64 * db_time as f64;
+    }
+    if sum_w == 0.0 {
+        return None;
+    }
+    let (mean_q, mean_d) = (sum_q / sum_w, sum_d / sum_w);
+
+    // 2. Slope from the weighted covariance, undefined when every hit shares a query time
+    let (mut cov, mut var) = (0.0f64, 0.0f64);
+    for &(query_time, db_time, weight) in aligned {
+        let dq = query_time as f64 - mean_q;
+        cov += weight as f64 * dq * (db_time as f64 - mean_d);
+        var += weight as f64 * dq * dq;
+    }
+    if var == 0.0 {
+        return None;
+    }
+    let slope = cov / var;
+    Some((slope as f32, (mean_d - slope * mean_q).round() as i64))
+}
+
+/// Hash index of a song catalogue, built and queried with the hashing strategy `F`.
 #[derive(Serialize, Deserialize)]
-pub struct AudioDatabase {
+#[serde(bound(deserialize = "F: Deserialize<'de> + Default"))]
+pub struct AudioDatabase<F = PairFingerprinter> {
     pub songs: HashMap<u32, String>,
     pub hashes: HashMap<u64, Vec<(u32, usize)>>,
     next_song_id: u32,
+    // Fields added later go last with a default, rmp_serde stores structs as arrays
+    /// Tags and technical details of each song, keyed like `songs`
+    #[serde(default)]
+    pub metadata: HashMap<u32, AudioMetadata>,
+    /// Meaning of the stored time offsets, used to report matches in seconds
+    #[serde(default)]
+    pub units: SpectrogramUnits,
+    /// Strategy and settings the hashes were built with, queries reuse it
+    #[serde(default)]
+    fingerprinter: F,
+    /// Decoding and peak settings the hashes were built with, queries reuse them
+    #[serde(default)]
+    pipeline: PipelineConfig,
+    /// Lower case extensions picked up by `index_directory`
+    #[serde(skip, default = "default_extensions")]
+    allowed_extensions: Vec<String>,
+}
+
+fn default_extensions() -> Vec<String> {
+    supported_extensions().into_iter().map(String::from).collect()
 }
 
 impl AudioDatabase {
+    /// An empty database using the pair hashes.
     pub fn new() -> Self {
+        Self::with_fingerprinter(PairFingerprinter::default())
+    }
+
+    /// Loads a database of pair hashes from a binary file on disk.
+    /// Use `load_from_file_as` for databases built with another fingerprinter.
+    pub fn load_from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_from_file_as(path)
+    }
+}
+
+impl<F> AudioDatabase<F>
+where
+    F: Fingerprinter + Clone + Default + Send + Sync + Serialize + DeserializeOwned + 'static,
+{
+    pub fn with_fingerprinter(fingerprinter: F) -> Self {
+        Self::with_pipeline(fingerprinter, PipelineConfig::default())
+    }
+
+    /// An empty database whose songs and queries all go through `pipeline`.
+    pub fn with_pipeline(fingerprinter: F, pipeline: PipelineConfig) -> Self {
         AudioDatabase {
             songs: HashMap::new(),
             hashes: HashMap::new(),
             next_song_id: 0,
+            metadata: HashMap::new(),
+            units: pipeline.units(),
+            fingerprinter,
+            pipeline,
+            allowed_extensions: default_extensions(),
         }
     }
 
+    pub fn fingerprinter(&self) -> &F {
+        &self.fingerprinter
+    }
+
+    pub fn pipeline(&self) -> &PipelineConfig {
+        &self.pipeline
+    }
+
+    /// Restricts indexing to the given extensions (case-insensitive, without the dot).
+    pub fn set_allowed_extensions<S: AsRef<str>>(&mut self, extensions: &[S]) {
+        self.allowed_extensions = extensions.iter()
+            .map(|ext| ext.as_ref().trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+    }
+
     /// Recursively traverses a directory and indexes all audio files
     pub fn index_directory(&mut self, directory: &str) {
+        self.index_files(directory, false);
+    }
+
+    /// Like `index_directory`, but every channel of a file becomes its own song
+    /// entry, named `<file> [ch <n>]`.
+    pub fn index_directory_per_channel(&mut self, directory: &str) {
+        self.index_files(directory, true);
+    }
+
+    fn index_files(&mut self, directory: &str, per_channel: bool) {
         let path = Path::new(directory);
         if !path.is_dir() {
             eprintln!("Error: {} is not a directory", directory);
@@ -45,6 +172,8 @@ impl AudioDatabase {
         // tx (Transmitter) can be cloned and given to many threads.
         // rx (Receiver) stays on the main thread.
         let (tx, rx) = mpsc::channel();
+        let fingerprinter = self.fingerprinter.clone();
+        let config = self.pipeline.clone();
 
         // 3. Process files in the background using Rayon's thread pool
         // We use thread::spawn so the main thread isn't blocked and can start
@@ -61,11 +190,23 @@ impl AudioDatabase {
                 println!("Thread {:?} started processing: {}", std::thread::current().id(), filename);
 
                 // Run the heavy audio pipeline (Decoding -> FFT -> Hashing)
-                let fingerprints = extract_features(&file_path, 1.0f32);
+                // Each file yields its metadata and one or more named fingerprint sets
+                let result = if per_channel {
+                    extract_peaks_per_channel(&file_path, &config).map(|(sets, metadata)| {
+                        let sets = sets.iter()
+                            .enumerate()
+                            .map(|(channel, peaks)| (format!("{} [ch {}]", filename, channel), fingerprinter.index(peaks)))
+                            .collect::<Vec<_>>();
+                        (sets, metadata)
+                    })
+                } else {
+                    extract_peaks_with_metadata(&file_path, &config)
+                        .map(|(peaks, metadata)| (vec![(filename.clone(), fingerprinter.index(&peaks))], metadata))
+                };
 
                 // Send the result back to the main thread
                 // If the receiver is dropped, send() fails, so we just ignore errors here
-                let _ = tx.send((filename, fingerprints));
+                let _ = tx.send((filename, result));
             });
             // The `tx` is dropped here when the parallel iterator finishes.
             // This signals the `rx` channel to close.
@@ -76,18 +217,30 @@ impl AudioDatabase {
 
         // This loop will block and wait for messages. It automatically exits
         // when all transmitters (`tx`) are dropped (i.e., when all files are done).
-        for (filename, fingerprints) in rx {
+        for (filename, result) in rx {
             processed_count += 1;
 
-            let song_id = self.next_song_id;
-            self.songs.insert(song_id, filename.clone());
-            self.next_song_id += 1;
-
-            for fp in fingerprints {
-                self.hashes
-                    .entry(fp.hash)
-                    .or_insert_with(Vec::new)
-                    .push((song_id, fp.time_offset));
+            // A file that fails to decode is reported and skipped, it doesn't abort the run
+            let (sets, metadata) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("Skipped {}/{}: {}", processed_count, total_files, e);
+                    continue;
+                }
+            };
+
+            for (name, fingerprints) in sets {
+                let song_id = self.next_song_id;
+                self.songs.insert(song_id, name);
+                self.metadata.insert(song_id, metadata.clone());
+                self.next_song_id += 1;
+
+                for fp in fingerprints {
+                    self.hashes
+                        .entry(fp.hash)
+                        .or_insert_with(Vec::new)
+                        .push((song_id, fp.time_offset));
+                }
             }
 
             println!("Merged {}/{} into DB: {}", processed_count, total_files, filename);
@@ -110,7 +263,7 @@ impl AudioDatabase {
                 } else {
                     let is_audio = path.extension()
                         .and_then(|ext| ext.to_str())
-                        .map(|ext| ext == "mp3" || ext == "wav")
+                        .map(|ext| self.allowed_extensions.iter().any(|allowed| allowed.eq_ignore_ascii_case(ext)))
                         .unwrap_or(false);
 
                     if is_audio {
@@ -121,11 +274,27 @@ impl AudioDatabase {
         }
     }
 
+    /// Decodes the part of a query recording selected by `load` with this database's
+    /// pipeline, then hashes and matches it with its fingerprinter.
+    pub fn identify(&self, song: &Path, load: &LoadOptions) -> Result<Option<String>, AudioLoadError> {
+        let config = PipelineConfig { load: load.clone(), ..self.pipeline.clone() };
+        let peaks = extract_peaks_with_config(song, &config)?;
+        Ok(self.find_best_match_peaks(&peaks))
+    }
+
+    /// Matches query peaks, which must be picked with `pipeline()`, for example from a
+    /// reader or raw PCM. They are hashed here with `fingerprinter().query`.
+    pub fn find_best_match_peaks(&self, query_peaks: &[SpectrogramPoint]) -> Option<String> {
+        self.find_best_match(&self.fingerprinter.query(query_peaks))
+    }
+
+    /// Matches query hashes, which must be built with the same settings as the database.
     pub fn find_best_match(&self, query_fingerprints: &[Fingerprint]) -> Option<String> {
         // We need to map: SongID -> (TimeDelta -> MatchCount)
         // We use i64 for the delta because the query could technically 
         // start slightly before the indexed song due to prepended silence/noise.
-        let mut match_counts: HashMap<u32, HashMap<i64, usize>> = HashMap::new();
+        // Fuzzy query hashes vote with `Fingerprint::weight`, so a near miss counts less than an exact hit
+        let mut match_counts: HashMap<u32, HashMap<i64, f32>> = HashMap::new();
 
         // 1. Iterate through every hash in our query snippet
         for query_fp in query_fingerprints {
@@ -141,15 +310,15 @@ impl AudioDatabase {
 
                     // Increment the histogram for this specific song and delta
                     let song_histogram = match_counts.entry(song_id).or_insert_with(HashMap::new);
-                    let count = song_histogram.entry(delta).or_insert(0);
-                    *count += 1;
+                    let count = song_histogram.entry(delta).or_insert(0.0);
+                    *count += query_fp.weight();
                 }
             }
         }
 
         // 2. Analyze the histograms to find the highest peak (max coherence)
         let mut best_song_id = None;
-        let mut max_aligned_matches = 0;
+        let mut max_aligned_matches = 0.0;
         let mut best_delta = 0;
 
         for (song_id, histogram) in match_counts {
@@ -163,25 +332,83 @@ impl AudioDatabase {
         }
 
         // 3. Apply a confidence threshold
-        // If we only have 3 or 4 random hashes align, it could be a coincidence.
-        // 10+ aligned hashes is statistically impossible to happen by chance.
-        let threshold = 10;
-
-        if max_aligned_matches >= threshold {
+        if max_aligned_matches >= MATCH_THRESHOLD {
             if let Some(id) = best_song_id {
                 if let Some(song_name) = self.songs.get(&id) {
                     println!(
-                        "Match found! '{}' with {} aligned hashes at time offset delta {}.",
-                        song_name, max_aligned_matches, best_delta
+                        "Match found! '{}' with {:.1} aligned hashes at time offset delta {} ({:.2}s).",
+                        song_name, max_aligned_matches, best_delta,
+                        self.units.frame_to_seconds(best_delta as f32)
                     );
                     return Some(song_name.clone());
                 }
             }
         }
 
-        println!("No match found. (Highest coherence was {} hashes)", max_aligned_matches);
+        println!("No match found. (Highest coherence was {:.1} hashes)", max_aligned_matches);
         None
     }
+    /// Like `find_best_match`, but also searches stretch factors between
+    /// `1 - max_deviation` and `1 + max_deviation` in steps of `step`.
+    ///
+    /// Meant for the invariant quad hashes, whose matches still line up on a
+    /// straight line when the query is sped up, just not with slope 1.
+    pub fn find_best_match_stretched(&self, query_peaks: &[SpectrogramPoint], max_deviation: f32, step: f32) -> Option<StretchMatch> {
+        let query_fingerprints = self.fingerprinter.query(query_peaks);
+
+        // 1. Collect the (query time, song time) pairs of every hit per song
+        let mut hits: HashMap<u32, Vec<(usize, usize, f32)>> = HashMap::new();
+        for query_fp in &query_fingerprints {
+            if let Some(db_matches) = self.hashes.get(&query_fp.hash) {
+                for &(song_id, db_time_offset) in db_matches {
+                    hits.entry(song_id).or_default().push((query_fp.time_offset, db_time_offset, query_fp.weight()));
+                }
+            }
+        }
+
+        // 2. For each stretch, histogram the offsets like the plain matcher does.
+        // Short queries give several stretches the same count, ties go to the one nearest 1
+        let steps = if step > 0.0 { (max_deviation / step).floor() as i64 } else { 0 };
+        let mut best: Option<(u32, f32, i64, f32)> = None;
+        for (song_id, pairs) in hits.iter() {
+            for k in -steps..=steps {
+                let stretch = 1.0 + k as f32 * step;
+                let mut histogram: HashMap<i64, f32> = HashMap::new();
+                for &(query_time, db_time, weight) in pairs {
+                    let offset = (db_time as f32 - stretch * query_time as f32).round() as i64;
+                    *histogram.entry(offset).or_insert(0.0) += weight;
+                }
+                if let Some((&offset, &count)) = histogram.iter().max_by(|a, b| a.1.total_cmp(b.1)) {
+                    let better = |&(_, best_stretch, _, best_count): &(u32, f32, i64, f32)| {
+                        count > best_count
+                            || (count == best_count && (stretch - 1.0).abs() < (best_stretch - 1.0).abs())
+                    };
+                    if best.is_none_or(|b| better(&b)) {
+                        best = Some((*song_id, stretch, offset, count));
+                    }
+                }
+            }
+        }
+
+        // 3. Same confidence threshold as `find_best_match`
+        match best {
+            Some((song_id, stretch, offset, aligned)) if aligned >= MATCH_THRESHOLD => {
+                // 4. The grid only brackets the stretch, fit a line through the aligned hits
+                let (stretch, offset) = fit_stretch(&hits[&song_id], stretch, offset).unwrap_or((stretch, offset));
+                let song = self.songs.get(&song_id)?.clone();
+                println!(
+                    "Match found! '{}' with {:.1} aligned hashes at stretch {:.3}, offset {} ({:.2}s).",
+                    song, aligned, stretch, offset, self.units.frame_to_seconds(offset as f32)
+                );
+                Some(StretchMatch { song, stretch, offset, aligned })
+            }
+            _ => {
+                println!("No match found. (Highest coherence was {:.1} hashes)", best.map_or(0.0, |b| b.3));
+                None
+            }
+        }
+    }
+
     pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
         let file = fs::File::create(path)?;
         // Wrap the file in a BufWriter for performance
@@ -194,14 +421,16 @@ impl AudioDatabase {
         Ok(())
     }
 
-    /// Loads the database from a binary file on disk.
-    pub fn load_from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Loads the database from a binary file on disk, `AudioDatabase::<F>::load_from_file_as(path)`.
+    pub fn load_from_file_as(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let file = fs::File::open(path)?;
         // Wrap the file in a BufReader for performance
         let reader = BufReader::new(file);
 
         // Deserialize the bytes back into our AudioDatabase struct
-        let db: AudioDatabase = rmp_serde::decode::from_read(reader)?;
+        let db: AudioDatabase<F> = rmp_serde::decode::from_read(reader)?;
+        // Files written before the settings were checked may hold unusable ones
+        F::with_config(db.fingerprinter.config().clone())?;
 
         println!(
             "Successfully loaded database from {}. ({} songs, {} unique hashes)",
@@ -1,57 +1,309 @@
-use rubato::{Resampler, SincFixedIn, SincInterpolationType, SincInterpolationParameters, WindowFunction};
+use std::fmt;
+use rubato::{
+    FftFixedIn, ResampleError, Resampler, ResamplerConstructionError, SincFixedIn, SincInterpolationType,
+    SincInterpolationParameters, WindowFunction,
+};
+use serde::{Serialize, Deserialize};
 
-pub fn downsample(input: &[f32], src_rate: u32, target_rate: u32) -> Vec<f32> {
-    if src_rate == target_rate {
-        return input.to_vec();
+// Input samples per batch
+const CHUNK_SIZE: usize = 1024;
+
+/// Failures reported by rubato.
+#[derive(Debug)]
+pub enum DownsampleError {
+    Construction(ResamplerConstructionError),
+    Process(ResampleError),
+}
+
+impl fmt::Display for DownsampleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownsampleError::Construction(e) => write!(f, "failed to create resampler: {}", e),
+            DownsampleError::Process(e) => write!(f, "resampling failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DownsampleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownsampleError::Construction(e) => Some(e),
+            DownsampleError::Process(e) => Some(e),
+        }
+    }
+}
+
+impl From<ResamplerConstructionError> for DownsampleError {
+    fn from(e: ResamplerConstructionError) -> Self {
+        DownsampleError::Construction(e)
+    }
+}
+
+impl From<ResampleError> for DownsampleError {
+    fn from(e: ResampleError) -> Self {
+        DownsampleError::Process(e)
+    }
+}
+
+/// Speed/quality trade-off of the resampler.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ResampleQuality {
+    /// FFT based synchronous resampler, cheapest for common rate pairs like 44.1 kHz to 11.025 kHz
+    Fast,
+    /// Short sinc filter
+    Balanced,
+    /// Long sinc filter, the original setting
+    #[default]
+    Best,
+}
+
+impl ResampleQuality {
+    pub const ALL: [ResampleQuality; 3] = [ResampleQuality::Fast, ResampleQuality::Balanced, ResampleQuality::Best];
+}
+
+/// The rubato engine behind a quality profile.
+enum Engine {
+    Sinc(SincFixedIn<f32>),
+    Fft(FftFixedIn<f32>),
+}
+
+impl Engine {
+    fn process(&mut self, input: &[Vec<f32>]) -> Result<Vec<Vec<f32>>, ResampleError> {
+        match self {
+            Engine::Sinc(resampler) => resampler.process(input, None),
+            Engine::Fft(resampler) => resampler.process(input, None),
+        }
     }
 
-    // 1. Calculate the resampling ratio
+    fn output_delay(&self) -> usize {
+        match self {
+            Engine::Sinc(resampler) => resampler.output_delay(),
+            Engine::Fft(resampler) => resampler.output_delay(),
+        }
+    }
+}
+
+fn build_resampler(src_rate: u32, target_rate: u32, quality: ResampleQuality) -> Result<Engine, DownsampleError> {
     let ratio = target_rate as f64 / src_rate as f64;
 
-    // 2. Configure the Resampler (High quality Sinc interpolation)
+    // Configure the Resampler
     // SincFixedIn is easier to use for arbitrary ratios than FFT-based ones.
-    let params = SincInterpolationParameters {
-        sinc_len: 256,
-        f_cutoff: 0.95, // Cut off at 95% of the Nyquist frequency to avoid aliasing
-        interpolation: SincInterpolationType::Linear,
-        oversampling_factor: 128,
-        window: WindowFunction::BlackmanHarris2,
+    let params = match quality {
+        ResampleQuality::Fast => {
+            // 1 Channel (Mono), 2 sub chunks per input chunk
+            let fft = FftFixedIn::<f32>::new(src_rate as usize, target_rate as usize, CHUNK_SIZE, 2, 1)?;
+            return Ok(Engine::Fft(fft));
+        }
+        ResampleQuality::Balanced => SincInterpolationParameters {
+            sinc_len: 64,
+            f_cutoff: 0.9,
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 64,
+            window: WindowFunction::Blackman2,
+        },
+        ResampleQuality::Best => SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95, // Cut off at 95% of the Nyquist frequency to avoid aliasing
+            interpolation: SincInterpolationType::Linear,
+            oversampling_factor: 128,
+            window: WindowFunction::BlackmanHarris2,
+        },
     };
 
     // 1 Channel (Mono), chunk size (input samples per batch)
-    let chunk_size = 1024;
-    let mut resampler = SincFixedIn::<f32>::new(
+    let sinc = SincFixedIn::<f32>::new(
         ratio,
         5f64,
         params,
-        chunk_size,
+        CHUNK_SIZE,
         1, // Channels
-    ).unwrap();
+    )?;
+    Ok(Engine::Sinc(sinc))
+}
+
+/// Mono resampler that keeps its filter state between pushes.
+///
+/// The filter delay is trimmed from the start of the output and `flush` cuts the
+/// tail so the total output is exactly `round(input_len * ratio)` samples, which
+/// keeps spectrogram frames aligned with the source timeline.
+pub struct Downsampler {
+    // None when the rates already match and samples are passed through
+    resampler: Option<Engine>,
+    ratio: f64,
+    pending: Vec<f32>,
+    input_frames: Vec<Vec<f32>>,
+    // Output samples still to drop to compensate for the filter delay
+    delay_remaining: usize,
+    input_len: usize,
+    output_len: usize,
+}
+
+impl Downsampler {
+    pub fn new(src_rate: u32, target_rate: u32, quality: ResampleQuality) -> Result<Self, DownsampleError> {
+        let ratio = target_rate as f64 / src_rate as f64;
+        let resampler = if src_rate == target_rate {
+            None
+        } else {
+            Some(build_resampler(src_rate, target_rate, quality)?)
+        };
+        let delay_remaining = resampler.as_ref().map(|r| r.output_delay()).unwrap_or(0);
+
+        Ok(Self {
+            resampler,
+            ratio,
+            pending: Vec::with_capacity(CHUNK_SIZE),
+            input_frames: vec![vec![0.0; CHUNK_SIZE]; 1], // Rubato expects Vec<Vec<f32>> (channels)
+            delay_remaining,
+            input_len: 0,
+            output_len: 0,
+        })
+    }
+
+    /// Output length implied by everything pushed so far.
+    fn expected_len(&self) -> usize {
+        self.expected_len_for(self.input_len)
+    }
 
-    // 3. Prepare buffers
-    let mut output_buffer = Vec::with_capacity((input.len() as f64 * ratio) as usize);
-    let mut input_frames = vec![vec![0.0; chunk_size]; 1]; // Rubato expects Vec<Vec<f32>> (channels)
+    fn expected_len_for(&self, input_len: usize) -> usize {
+        (input_len as f64 * self.ratio).round() as usize
+    }
+
+    /// Resamples every complete chunk of `input` and appends the result to `out`.
+    /// Leftover samples are kept until the next push or `flush`.
+    pub fn push(&mut self, input: &[f32], out: &mut Vec<f32>) -> Result<(), DownsampleError> {
+        self.input_len += input.len();
+        if self.resampler.is_none() {
+            out.extend_from_slice(input);
+            self.output_len += input.len();
+            return Ok(());
+        }
+
+        let mut input = input;
+        while !input.is_empty() {
+            let take = (CHUNK_SIZE - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..take]);
+            input = &input[take..];
+
+            if self.pending.len() == CHUNK_SIZE {
+                self.input_frames[0].copy_from_slice(&self.pending);
+                self.pending.clear();
+                self.process_chunk(out)?;
+            }
+        }
+        Ok(())
+    }
 
-    // 4. Process chunks
-    for chunk in input.chunks(chunk_size) {
-        // Copy chunk into input_frames
-        // If the last chunk is smaller than chunk_size, we must pad it with zeros
-        let current_len = chunk.len();
-        input_frames[0][..current_len].copy_from_slice(chunk);
-        if current_len < chunk_size {
-            input_frames[0][current_len..].fill(0.0);
+    /// Pushes the buffered tail through the filter and trims the output to its exact length.
+    pub fn flush(&mut self, out: &mut Vec<f32>) -> Result<(), DownsampleError> {
+        if self.resampler.is_none() {
+            return Ok(());
         }
 
-        // Process
-        // `process` returns the resampled audio for this chunk
-        // Note: The last chunk might return slightly more silence due to padding, 
-        // but for fingerprinting, this is negligible.
-        let output_frames = resampler.process(&input_frames, None).unwrap();
+        // Zero padding drives the remaining input (and the filter delay) out of the resampler
+        let expected = self.expected_len();
+        let current_len = self.pending.len();
+        self.input_frames[0][..current_len].copy_from_slice(&self.pending);
+        self.input_frames[0][current_len..].fill(0.0);
+        self.pending.clear();
+
+        let start = out.len();
+        let missing = expected.saturating_sub(self.output_len);
+        while out.len() - start < missing {
+            self.process_chunk(out)?;
+            self.input_frames[0].fill(0.0);
+        }
+
+        // Drop the resampled padding
+        out.truncate(start + missing);
+        self.output_len = expected;
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, out: &mut Vec<f32>) -> Result<(), DownsampleError> {
+        let resampler = match self.resampler.as_mut() {
+            Some(resampler) => resampler,
+            None => return Ok(()),
+        };
+        let output_frames = resampler.process(&self.input_frames)?;
+
+        // The first samples out of the filter belong to before the input started
+        let skip = self.delay_remaining.min(output_frames[0].len());
+        self.delay_remaining -= skip;
+        out.extend_from_slice(&output_frames[0][skip..]);
+        self.output_len += output_frames[0].len() - skip;
+        Ok(())
+    }
+}
+
+/// Iterator adapter that resamples a stream of mono blocks.
+///
+/// A resampling error ends the stream early, check `error()` once it is drained.
+pub struct DownsampleStream<I: Iterator<Item = Vec<f32>>> {
+    input: I,
+    downsampler: Downsampler,
+    error: Option<DownsampleError>,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = Vec<f32>>> DownsampleStream<I> {
+    pub fn new(input: I, src_rate: u32, target_rate: u32, quality: ResampleQuality) -> Result<Self, DownsampleError> {
+        Ok(Self {
+            input,
+            downsampler: Downsampler::new(src_rate, target_rate, quality)?,
+            error: None,
+            finished: false,
+        })
+    }
 
-        // Append mono channel to output
-        output_buffer.extend_from_slice(&output_frames[0]);
+    /// The error that stopped the stream, if any.
+    pub fn error(&self) -> Option<&DownsampleError> {
+        self.error.as_ref()
     }
 
-    // Optional: Trim excess capacity or silence if strict length is needed
-    output_buffer
-}
\ No newline at end of file
+    pub fn take_error(&mut self) -> Option<DownsampleError> {
+        self.error.take()
+    }
+}
+
+impl<I: Iterator<Item = Vec<f32>>> Iterator for DownsampleStream<I> {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        let mut out = Vec::new();
+        // Small input blocks may not complete a chunk, keep pulling until we have output
+        while out.is_empty() && !self.finished {
+            let result = match self.input.next() {
+                Some(block) => self.downsampler.push(&block, &mut out),
+                None => {
+                    self.finished = true;
+                    self.downsampler.flush(&mut out)
+                }
+            };
+            if let Err(e) = result {
+                self.error = Some(e);
+                self.finished = true;
+                return None;
+            }
+        }
+
+        if out.is_empty() { None } else { Some(out) }
+    }
+}
+
+pub fn downsample(input: &[f32], src_rate: u32, target_rate: u32) -> Result<Vec<f32>, DownsampleError> {
+    downsample_with_quality(input, src_rate, target_rate, ResampleQuality::default())
+}
+
+pub fn downsample_with_quality(
+    input: &[f32],
+    src_rate: u32,
+    target_rate: u32,
+    quality: ResampleQuality,
+) -> Result<Vec<f32>, DownsampleError> {
+    let mut downsampler = Downsampler::new(src_rate, target_rate, quality)?;
+    let mut output_buffer = Vec::with_capacity(downsampler.expected_len_for(input.len()));
+
+    downsampler.push(input, &mut output_buffer)?;
+    downsampler.flush(&mut output_buffer)?;
+    Ok(output_buffer)
+}
@@ -1,13 +1,36 @@
+use std::collections::VecDeque;
+use crate::create_spectogram::ColumnStream;
 use crate::types::types::SpectrogramPoint;
+use serde::{Serialize, Deserialize};
 
 
 
 const BUFFER_SIZE:usize = 60;
 
 const CHUNKS: [(usize, usize); 6]  = [(0, 10), (10, 20), (20, 40), (40, 80), (80, 160), (160, 512)];
+// Bin count the CHUNKS edges were chosen for
+const REFERENCE_BINS: usize = 512;
+
+/// Scales the CHUNKS band edges to a spectrogram with `bins` frequency bins.
+/// With 512 bins this returns CHUNKS unchanged.
+pub fn band_edges(bins: usize) -> Vec<(usize, usize)> {
+    let scale = |edge: usize| (edge * bins + REFERENCE_BINS / 2) / REFERENCE_BINS;
+
+    let mut bands = Vec::with_capacity(CHUNKS.len());
+    let mut start = 0;
+    for (_, end) in CHUNKS.iter() {
+        // Every band keeps at least one bin, small FFTs would otherwise collapse the bass bands
+        let end = scale(*end).max(start + 1).min(bins);
+        if start < end {
+            bands.push((start, end));
+        }
+        start = end;
+    }
+    bands
+}
 
 pub struct RollingStats {
-    buffer: [f32; BUFFER_SIZE],
+    buffer: Vec<f32>,
     cursor: usize,
     count: usize,
     sum: f32,
@@ -17,8 +40,13 @@ pub struct RollingStats {
 impl RollingStats {
     /// Creates a new, empty RollingStats structure.
     pub fn new() -> Self {
+        Self::with_window(BUFFER_SIZE)
+    }
+
+    /// Empty statistics over the last `window` values (at least one).
+    pub fn with_window(window: usize) -> Self {
         Self{
-            buffer: [0.0; BUFFER_SIZE],
+            buffer: vec![0.0; window.max(1)],
             cursor: 0,
             count: 0,
             sum: 0.0,
@@ -28,8 +56,9 @@ impl RollingStats {
 
     /// Adds a new value to the rolling window.
     pub fn push(&mut self, value: f32) {
+        let window = self.buffer.len();
         // If the buffer is full, we must remove the oldest value from our running sums
-        let old_value = if self.count == BUFFER_SIZE {
+        let old_value = if self.count == window {
             self.buffer[self.cursor]
         } else {
             0.0
@@ -41,9 +70,9 @@ impl RollingStats {
 
         // Overwrite the oldest value and move the cursor
         self.buffer[self.cursor] = value;
-        self.cursor = (self.cursor + 1) % BUFFER_SIZE;
+        self.cursor = (self.cursor + 1) % window;
 
-        if self.count < BUFFER_SIZE {
+        if self.count < window {
             self.count += 1;
         }
     }
@@ -73,23 +102,303 @@ impl RollingStats {
 
         variance.max(0.0).sqrt()
     }
+
+    /// Value below which `percentile` percent of the current values fall.
+    /// Falls back to `mean()` while the window is empty. The window is copied into
+    /// `scratch`, pass the same one every frame so the peak loop doesn't allocate.
+    pub fn percentile(&self, percentile: f32, scratch: &mut Vec<f32>) -> f32 {
+        if self.count == 0 {
+            return self.mean();
+        }
+        scratch.clear();
+        scratch.extend_from_slice(&self.buffer[..self.count]);
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * (self.count - 1) as f32).round() as usize;
+        let (_, value, _) = scratch.select_nth_unstable_by(rank, |a, b| a.total_cmp(b));
+        *value
+    }
+
     pub fn get_threshold(&self, modifier: f32) -> f32{
         self.mean() + modifier*self.std_dev()
     }
 }
 
+/// How a band's threshold is derived from its recent maxima.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ThresholdRule {
+    /// mean + k * std of the window, the original rule
+    MeanStd { k: f32 },
+    /// The given percentile (0-100) of the window
+    Percentile { percentile: f32 },
+    /// An envelope that jumps to each band maximum and shrinks by `decay` every frame,
+    /// a peak has to beat the decayed envelope
+    Decay { decay: f32 },
+}
 
-pub fn save_spectrogram_peaks(
-    spectrogram: &[Vec<f32>],
-    modifier : f32
-) -> Vec<SpectrogramPoint> {
-    let mut ret: Vec<SpectrogramPoint> = Vec::new();
-    let mut track = RollingStats::new();
+/// Thresholds of the band-max picker.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BandThresholdConfig {
+    /// Frames of band maxima the statistics look back over
+    pub window: usize,
+    pub rule: ThresholdRule,
+    /// Judge every band against its own history instead of one pool shared by all bands
+    pub per_band: bool,
+}
+
+impl Default for BandThresholdConfig {
+    fn default() -> Self {
+        Self {
+            window: BUFFER_SIZE,
+            rule: ThresholdRule::MeanStd { k: 1.0 },
+            per_band: true,
+        }
+    }
+}
+
+/// Threshold history of one band, or of all bands when they share it.
+struct BandState {
+    stats: RollingStats,
+    envelope: f32,
+    // Reused by the percentile rule
+    scratch: Vec<f32>,
+}
+
+impl BandState {
+    fn new(window: usize) -> Self {
+        Self { stats: RollingStats::with_window(window), envelope: 0.0, scratch: Vec::new() }
+    }
+
+    fn threshold(&mut self, rule: ThresholdRule) -> f32 {
+        match rule {
+            ThresholdRule::MeanStd { k } => self.stats.get_threshold(k),
+            ThresholdRule::Percentile { percentile } => self.stats.percentile(percentile, &mut self.scratch),
+            ThresholdRule::Decay { decay } => self.envelope * decay,
+        }
+    }
+}
+
+
+/// Strategy that turns spectrogram columns into constellation peaks.
+///
+/// Columns arrive one at a time. A picker may hold peaks back until it has seen
+/// enough later columns, `finish` releases whatever is left once the input ends.
+pub trait PeakPicker {
+    /// Feeds the next column and appends every peak that became final to `out`.
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>);
+
+    /// Appends the peaks still waiting for columns that will never come.
+    fn finish(&mut self, _out: &mut Vec<SpectrogramPoint>) {}
+
+    /// Most columns a peak can be held back after its own column was pushed.
+    fn delay(&self) -> usize {
+        0
+    }
+}
+
+impl<P: PeakPicker + ?Sized> PeakPicker for Box<P> {
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        (**self).push_column(column, out);
+    }
+
+    fn finish(&mut self, out: &mut Vec<SpectrogramPoint>) {
+        (**self).finish(out);
+    }
+
+    fn delay(&self) -> usize {
+        (**self).delay()
+    }
+}
+
+/// Vertex of the parabola through three neighbouring values around the peak `centre`.
+/// Returns the offset in [-0.5, 0.5] and the magnitude gained, or `None` when the
+/// values don't bend down around the centre.
+fn parabolic_vertex(before: f32, centre: f32, after: f32) -> Option<(f32, f32)> {
+    let curvature = before - 2.0 * centre + after;
+    if curvature >= 0.0 {
+        return None;
+    }
+    let offset = (0.5 * (before - after) / curvature).clamp(-0.5, 0.5);
+    Some((offset, -0.25 * (before - after) * offset))
+}
+
+/// Copies `column` into a buffer taken back from `spare`, so pickers that keep
+/// columns around don't allocate one per frame.
+fn buffered_column(spare: &mut Vec<Vec<f32>>, column: &[f32]) -> Vec<f32> {
+    let mut buffer = spare.pop().unwrap_or_default();
+    buffer.clear();
+    buffer.extend_from_slice(column);
+    buffer
+}
+
+/// Wraps another picker and moves each of its peaks to the vertex of the parabola
+/// through its neighbours, along frequency and along time.
+///
+/// Peaks then survive small pitch or sample-rate drift that would move them across
+/// a bin boundary. The integer `freq_bin` and `time_idx` are kept as picked.
+pub struct ParabolicRefiner<P: PeakPicker> {
+    inner: P,
+    columns: VecDeque<Vec<f32>>,
+    // Dropped columns, reused for the next ones
+    spare: Vec<Vec<f32>>,
+    // time_idx of columns[0]
+    first: usize,
+    // Peaks waiting for the column after theirs
+    pending: VecDeque<SpectrogramPoint>,
+    // Peaks of the inner picker for the current column
+    found: Vec<SpectrogramPoint>,
+}
+
+impl<P: PeakPicker> ParabolicRefiner<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            columns: VecDeque::new(),
+            spare: Vec::new(),
+            first: 0,
+            pending: VecDeque::new(),
+            found: Vec::new(),
+        }
+    }
+
+    fn value(&self, time: usize, bin: usize) -> Option<f32> {
+        let pos = time.checked_sub(self.first)?;
+        self.columns.get(pos)?.get(bin).copied()
+    }
 
-    for (x, column) in spectrogram.iter().enumerate() {
-        let mut inter: Vec<SpectrogramPoint> = Vec::new();
+    fn refine(&self, peak: &mut SpectrogramPoint) {
+        let (t, bin) = (peak.time_idx, peak.freq_bin);
+        let centre = match self.value(t, bin) {
+            Some(centre) => centre,
+            None => return,
+        };
+        let neighbours = |a: Option<f32>, b: Option<f32>| a.zip(b).and_then(|(a, b)| parabolic_vertex(a, centre, b));
+
+        let mut magnitude = centre;
+        let below = bin.checked_sub(1).and_then(|b| self.value(t, b));
+        if let Some((offset, gain)) = neighbours(below, self.value(t, bin + 1)) {
+            peak.freq_pos = bin as f32 + offset;
+            magnitude += gain;
+        }
+        let before = t.checked_sub(1).and_then(|t| self.value(t, bin));
+        if let Some((offset, gain)) = neighbours(before, self.value(t + 1, bin)) {
+            peak.time_pos = t as f32 + offset;
+            magnitude += gain;
+        }
+        peak.magnitude = magnitude;
+    }
 
-        for (start, end) in CHUNKS.iter() {
+    /// Refines every pending peak whose following column is known, or all of them at the end.
+    fn release(&mut self, all: bool, out: &mut Vec<SpectrogramPoint>) {
+        let known = self.first + self.columns.len();
+        while let Some(mut peak) = self.pending.pop_front() {
+            if !all && peak.time_idx + 1 >= known {
+                self.pending.push_front(peak);
+                break;
+            }
+            self.refine(&mut peak);
+            out.push(peak);
+        }
+
+        // Keep one column before the oldest frame that can still be asked for
+        let next_peak = known.saturating_sub(1 + self.inner.delay());
+        let oldest = self.pending.front().map(|p| p.time_idx).unwrap_or(next_peak).min(next_peak);
+        while self.first + 1 < oldest {
+            let Some(column) = self.columns.pop_front() else { break };
+            self.spare.push(column);
+            self.first += 1;
+        }
+    }
+}
+
+impl<P: PeakPicker> PeakPicker for ParabolicRefiner<P> {
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        self.columns.push_back(buffered_column(&mut self.spare, column));
+        self.inner.push_column(column, &mut self.found);
+        self.pending.extend(self.found.drain(..));
+        self.release(false, out);
+    }
+
+    fn finish(&mut self, out: &mut Vec<SpectrogramPoint>) {
+        let mut peaks = Vec::new();
+        self.inner.finish(&mut peaks);
+        self.pending.extend(peaks);
+        self.release(true, out);
+    }
+
+    fn delay(&self) -> usize {
+        self.inner.delay() + 1
+    }
+}
+
+/// Incremental band-max peak picker, fed one spectrogram column at a time.
+pub struct PeakFinder {
+    config: BandThresholdConfig,
+    // One entry per band, or a single shared one
+    states: Vec<BandState>,
+    // Band edges for the bin count of the columns seen so far
+    bands: Vec<(usize, usize)>,
+    band_counts: Vec<usize>,
+    time_idx: usize,
+    // Per-column buffers, kept so the peak loop doesn't allocate
+    maxima: Vec<SpectrogramPoint>,
+    thresholds: Vec<f32>,
+}
+
+impl PeakFinder {
+    /// The original picker: one pool of statistics shared by every band,
+    /// matching databases built before per-band thresholds.
+    pub fn new(modifier: f32) -> Self {
+        Self::with_config(BandThresholdConfig {
+            rule: ThresholdRule::MeanStd { k: modifier },
+            per_band: false,
+            ..BandThresholdConfig::default()
+        })
+    }
+
+    pub fn with_config(config: BandThresholdConfig) -> Self {
+        Self {
+            config,
+            states: Vec::new(),
+            bands: Vec::new(),
+            band_counts: Vec::new(),
+            time_idx: 0,
+            maxima: Vec::new(),
+            thresholds: Vec::new(),
+        }
+    }
+
+    /// Band edges in bins, in the order of `band_counts`.
+    pub fn bands(&self) -> &[(usize, usize)] {
+        &self.bands
+    }
+
+    /// Peaks each band has contributed so far.
+    pub fn band_counts(&self) -> &[usize] {
+        &self.band_counts
+    }
+
+    /// Picks the peaks of the next column and appends them to `out`.
+    pub fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        PeakPicker::push_column(self, column, out);
+    }
+}
+
+impl PeakPicker for PeakFinder {
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        let x = self.time_idx;
+        self.time_idx += 1;
+
+        if self.bands.last().map(|&(_, end)| end) != Some(column.len()) {
+            // A new layout starts the statistics over
+            self.bands = band_edges(column.len());
+            let states = if self.config.per_band { self.bands.len() } else { 1 };
+            self.states = (0..states).map(|_| BandState::new(self.config.window)).collect();
+            self.band_counts = vec![0; self.bands.len()];
+        }
+        let per_band = self.config.per_band;
+        let state_of = move |band: usize| if per_band { band } else { 0 };
+
+        self.maxima.clear();
+        for (band, (start, end)) in self.bands.iter().enumerate() {
             let mut max_j = 0;
             let mut max_mag = f32::MIN;
             for j in *start..*end {
@@ -98,16 +407,285 @@ pub fn save_spectrogram_peaks(
                     max_j = j;
                 }
             }
-            inter.push(SpectrogramPoint { freq_bin: max_j, magnitude: max_mag, time_idx: x });
-            track.push(max_mag);
+            self.maxima.push(SpectrogramPoint::new(max_j, max_mag, x));
+            self.states[state_of(band)].stats.push(max_mag);
         }
-        for pt in inter {
-            if pt.magnitude > track.get_threshold(modifier) {
-                ret.push(pt);
+
+        // Thresholds see this column's maxima, except the decay envelope which they would reset
+        let rule = self.config.rule;
+        self.thresholds.clear();
+        self.thresholds.extend(self.states.iter_mut().map(|state| state.threshold(rule)));
+        if let ThresholdRule::Decay { decay } = self.config.rule {
+            for state in self.states.iter_mut() {
+                state.envelope *= decay;
+            }
+            for (band, pt) in self.maxima.iter().enumerate() {
+                let state = &mut self.states[state_of(band)];
+                state.envelope = state.envelope.max(pt.magnitude);
+            }
+        }
+
+        for (band, pt) in self.maxima.drain(..).enumerate() {
+            if pt.magnitude > self.thresholds[state_of(band)] {
+                self.band_counts[band] += 1;
+                out.push(pt);
             }
         }
     }
-    ret
 }
 
+/// Neighbourhood and floors of the 2-D local-maximum detector.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LocalMaxConfig {
+    /// Frames on each side of a peak it has to dominate
+    pub time_radius: usize,
+    /// Bins on each side of a peak it has to dominate
+    pub freq_radius: usize,
+    /// Absolute floor, maxima at or below it are ignored (so silence yields nothing)
+    pub min_magnitude: f32,
+    /// A peak must reach this multiple of the mean magnitude of its neighbourhood,
+    /// which drops the maxima of flat noise regions
+    pub region_floor: f32,
+}
+
+impl Default for LocalMaxConfig {
+    fn default() -> Self {
+        Self {
+            time_radius: 5,
+            freq_radius: 10,
+            min_magnitude: 0.0,
+            region_floor: 2.0,
+        }
+    }
+}
+
+/// Max-filter constellation: a bin is a peak when nothing in the
+/// `(2 * time_radius + 1) x (2 * freq_radius + 1)` box around it is louder.
+///
+/// Only `time_radius` columns past the current one are buffered, so peaks are
+/// reported with that much delay.
+pub struct LocalMaxFinder {
+    config: LocalMaxConfig,
+    columns: VecDeque<Vec<f32>>,
+    // Dropped columns, reused for the next ones
+    spare: Vec<Vec<f32>>,
+    // time_idx of columns[0]
+    first: usize,
+    // Next column to decide
+    next: usize,
+}
 
+impl LocalMaxFinder {
+    pub fn new(config: LocalMaxConfig) -> Self {
+        Self {
+            columns: VecDeque::with_capacity(2 * config.time_radius + 1),
+            spare: Vec::new(),
+            config,
+            first: 0,
+            next: 0,
+        }
+    }
+
+    /// Finds the peaks of column `self.next` using whatever neighbours are buffered.
+    fn decide(&mut self, out: &mut Vec<SpectrogramPoint>) {
+        let t = self.next;
+        let pos = t - self.first;
+        let lo = pos.saturating_sub(self.config.time_radius);
+        let hi = (pos + self.config.time_radius).min(self.columns.len() - 1);
+        let column = &self.columns[pos];
+
+        for (bin, &magnitude) in column.iter().enumerate() {
+            if magnitude <= self.config.min_magnitude {
+                continue;
+            }
+            let f_lo = bin.saturating_sub(self.config.freq_radius);
+            let f_hi = bin + self.config.freq_radius;
+
+            let mut is_max = true;
+            let mut sum = 0f32;
+            let mut count = 0usize;
+            'region: for (p, other) in self.columns.range(lo..=hi).enumerate() {
+                let p = lo + p;
+                for (b, &value) in other.iter().enumerate().take(f_hi + 1).skip(f_lo) {
+                    // On a plateau the earliest, lowest bin wins so it yields a single peak
+                    if value > magnitude || (value == magnitude && (p, b) < (pos, bin)) {
+                        is_max = false;
+                        break 'region;
+                    }
+                    sum += value;
+                    count += 1;
+                }
+            }
+
+            if is_max && magnitude >= self.config.region_floor * sum / count as f32 {
+                out.push(SpectrogramPoint::new(bin, magnitude, t));
+            }
+        }
+
+        // Forget the columns no later decision can reach
+        self.next += 1;
+        while self.first + self.config.time_radius < self.next {
+            let Some(column) = self.columns.pop_front() else { break };
+            self.spare.push(column);
+            self.first += 1;
+        }
+    }
+}
+
+impl PeakPicker for LocalMaxFinder {
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        self.columns.push_back(buffered_column(&mut self.spare, column));
+        // A column is final once `time_radius` columns after it are known
+        while self.next + self.config.time_radius < self.first + self.columns.len() {
+            self.decide(out);
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<SpectrogramPoint>) {
+        while self.next < self.first + self.columns.len() {
+            self.decide(out);
+        }
+    }
+
+    fn delay(&self) -> usize {
+        self.config.time_radius
+    }
+}
+
+/// Target of the density mode.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DensityConfig {
+    /// Peaks kept per second of audio, whatever its loudness
+    pub peaks_per_second: f32,
+    /// Length of the blocks the budget is spent in, shorter blocks spread peaks more evenly
+    pub block_seconds: f32,
+    /// Candidates to choose from, kept permissive so quiet passages still offer enough
+    pub candidates: LocalMaxConfig,
+}
+
+impl Default for DensityConfig {
+    fn default() -> Self {
+        Self {
+            peaks_per_second: 30.0,
+            block_seconds: 1.0,
+            candidates: LocalMaxConfig {
+                time_radius: 2,
+                freq_radius: 3,
+                min_magnitude: 0.0,
+                region_floor: 1.0,
+            },
+        }
+    }
+}
+
+/// Keeps the loudest candidates of each time block so every track ends up with
+/// about `peaks_per_second` peaks, instead of a count that follows its loudness.
+pub struct DensityPicker<P: PeakPicker> {
+    inner: P,
+    block_frames: usize,
+    // Peaks owed per frame
+    rate: f32,
+    // Fractional peaks carried over so rounding doesn't bias the density
+    budget: f32,
+    block_start: usize,
+    // Candidates of the open blocks
+    pending: Vec<SpectrogramPoint>,
+    columns: usize,
+}
+
+impl<P: PeakPicker> DensityPicker<P> {
+    /// `frames_per_second` converts the targets from seconds to columns.
+    pub fn new(inner: P, config: &DensityConfig, frames_per_second: f32) -> Self {
+        Self {
+            inner,
+            block_frames: ((config.block_seconds * frames_per_second).round() as usize).max(1),
+            rate: config.peaks_per_second / frames_per_second,
+            budget: 0.0,
+            block_start: 0,
+            pending: Vec::new(),
+            columns: 0,
+        }
+    }
+
+    /// Releases the best candidates of the current block, which spans `frames` columns.
+    fn close_block(&mut self, frames: usize, out: &mut Vec<SpectrogramPoint>) {
+        self.budget += self.rate * frames as f32;
+        let keep = self.budget.floor().max(0.0) as usize;
+        self.budget -= keep as f32;
+
+        let end = self.block_start + frames;
+        let (mut block, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.time_idx < end);
+        self.pending = later;
+
+        block.sort_by(|a, b| b.magnitude.total_cmp(&a.magnitude));
+        block.truncate(keep);
+        block.sort_by_key(|p| (p.time_idx, p.freq_bin));
+        out.extend(block);
+        self.block_start = end;
+    }
+}
+
+impl<P: PeakPicker> PeakPicker for DensityPicker<P> {
+    fn push_column(&mut self, column: &[f32], out: &mut Vec<SpectrogramPoint>) {
+        self.columns += 1;
+        self.inner.push_column(column, &mut self.pending);
+
+        // A block is complete once the inner picker can no longer add to it
+        while self.block_start + self.block_frames + self.inner.delay() <= self.columns {
+            self.close_block(self.block_frames, out);
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<SpectrogramPoint>) {
+        self.inner.finish(&mut self.pending);
+
+        // Whole blocks left, then the partial one with a proportional share
+        while self.block_start + self.block_frames <= self.columns {
+            self.close_block(self.block_frames, out);
+        }
+        if self.block_start < self.columns {
+            self.close_block(self.columns - self.block_start, out);
+        }
+    }
+
+    fn delay(&self) -> usize {
+        self.block_frames + self.inner.delay()
+    }
+}
+
+/// Accepts a whole spectrogram or any iterator of columns, such as a `SpectrogramStream`.
+pub fn save_spectrogram_peaks<C: AsRef<[f32]>>(
+    spectrogram: impl IntoIterator<Item = C>,
+    modifier : f32
+) -> Vec<SpectrogramPoint> {
+    pick_peaks(spectrogram, &mut PeakFinder::new(modifier))
+}
+
+/// Runs any `PeakPicker` over a spectrogram or a stream of columns.
+pub fn pick_peaks<C: AsRef<[f32]>, P: PeakPicker + ?Sized>(
+    spectrogram: impl IntoIterator<Item = C>,
+    picker: &mut P,
+) -> Vec<SpectrogramPoint> {
+    let mut ret: Vec<SpectrogramPoint> = Vec::new();
+    for column in spectrogram {
+        picker.push_column(column.as_ref(), &mut ret);
+    }
+    picker.finish(&mut ret);
+    ret
+}
+
+/// Like `pick_peaks`, but every column of `stream` is written into the same buffer.
+pub fn pick_peaks_from_stream<S: ColumnStream + ?Sized, P: PeakPicker + ?Sized>(
+    stream: &mut S,
+    picker: &mut P,
+) -> Vec<SpectrogramPoint> {
+    let mut ret: Vec<SpectrogramPoint> = Vec::new();
+    let mut column = vec![0f32; stream.bins()];
+    while stream.next_into(&mut column) {
+        picker.push_column(&column, &mut ret);
+    }
+    picker.finish(&mut ret);
+    ret
+}
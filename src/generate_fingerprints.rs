@@ -1,4 +1,8 @@
 
+use std::collections::HashMap;
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use crate::hash_layout::{InvariantQuadHash, OverflowPolicy, PairHash, QuadHash};
 use crate::types::types::{Constellation, Fingerprint};
 use crate::types::types::SpectrogramPoint;
 
@@ -17,142 +21,661 @@ impl Constellation {
     
 }
 
-pub fn generate_fingerprints(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
-    let mut fingerprints = Vec::new();
+/// Grid peak positions are snapped to before they go into a hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum HashResolution {
+    /// The integer bin and frame the peak was picked at, the original hashes
+    #[default]
+    Bins,
+    /// Interpolated positions rounded to multiples of `freq_step` bins and `time_step` frames.
+    /// Steps above 1.0 absorb drift, steps below 1.0 need wider hash fields
+    Fractional { freq_step: f32, time_step: f32 },
+}
+
+/// Fingerprinter settings that can't produce usable hashes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FingerprintConfigError {
+    /// A `HashResolution::Fractional` step or a bucket width that is zero, negative or not finite
+    InvalidStep { field: &'static str, step: f32 },
+    /// Quantisation levels that don't fit the hash field
+    LevelsOutOfRange { levels: u32, max: u32 },
+}
+
+impl fmt::Display for FingerprintConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FingerprintConfigError::InvalidStep { field, step } => {
+                write!(f, "{} is {}, it must be a finite number above 0", field, step)
+            }
+            FingerprintConfigError::LevelsOutOfRange { levels, max } => {
+                write!(f, "{} quantisation levels, it must be between 1 and {}", levels, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FingerprintConfigError {}
+
+impl HashResolution {
+    /// Checks the steps, a zero step would push every hash out of its field.
+    pub fn validate(self) -> Result<(), FingerprintConfigError> {
+        if let HashResolution::Fractional { freq_step, time_step } = self {
+            for (field, step) in [("freq_step", freq_step), ("time_step", time_step)] {
+                if !step.is_finite() || step <= 0.0 {
+                    return Err(FingerprintConfigError::InvalidStep { field, step });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn freq(self, peak: &SpectrogramPoint) -> u64 {
+        match self {
+            HashResolution::Bins => peak.freq_bin as u64,
+            HashResolution::Fractional { freq_step, .. } => (peak.freq_pos / freq_step).round().max(0.0) as u64,
+        }
+    }
+
+    /// Time from `anchor` to `target`, zero if the target comes first.
+    fn time_delta(self, anchor: &SpectrogramPoint, target: &SpectrogramPoint) -> u64 {
+        match self {
+            HashResolution::Bins => target.time_idx.saturating_sub(anchor.time_idx) as u64,
+            HashResolution::Fractional { time_step, .. } => {
+                ((target.time_pos - anchor.time_pos) / time_step).round().max(0.0) as u64
+            }
+        }
+    }
+}
+
+/// A hashing strategy, turning the peaks of a song into fingerprints.
+///
+/// Indexing and querying go through the same value so both sides always hash
+/// with the same layout. The query side may add extra hashes, like fuzzy variants.
+pub trait Fingerprinter {
+    type Config: Clone + Default;
+
+    /// Builds the fingerprinter, rejecting settings that can't produce usable hashes.
+    fn with_config(config: Self::Config) -> Result<Self, FingerprintConfigError>
+    where
+        Self: Sized;
+
+    fn config(&self) -> &Self::Config;
+
+    /// Hashes stored in the database for an indexed song.
+    fn index(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint>;
+
+    /// Hashes looked up for a query snippet.
+    fn query(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        self.index(peaks)
+    }
+}
+
+/// Targets chosen by distance instead of by peak index.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TargetZone {
+    /// Closest target in frames, 1 or more keeps targets out of the anchor's frame
+    pub min_frames: usize,
+    /// Farthest target in frames
+    pub max_frames: usize,
+    /// Largest bin distance between the anchor and a target
+    pub max_freq_delta: usize,
+    /// At most this many targets per anchor, the loudest ones are kept
+    pub fan_out: usize,
+}
+
+impl Default for TargetZone {
+    /// Up to about two seconds ahead at the default 11025 Hz / 512 hop.
+    fn default() -> Self {
+        Self { min_frames: 1, max_frames: 40, max_freq_delta: 128, fan_out: 10 }
+    }
+}
+
+impl TargetZone {
+    /// Indices and peaks inside the zone of `peaks[anchor]`, in time order.
+    fn candidates<'a>(&self, peaks: &'a [SpectrogramPoint], anchor: usize) -> impl Iterator<Item = (usize, &'a SpectrogramPoint)> {
+        let a = &peaks[anchor];
+        let (min_frames, max_frames, max_freq_delta) = (self.min_frames, self.max_frames, self.max_freq_delta);
+        peaks[anchor + 1..]
+            .iter()
+            .enumerate()
+            .map(move |(offset, p)| (anchor + 1 + offset, p))
+            .take_while(move |(_, p)| p.time_idx <= a.time_idx + max_frames)
+            .filter(move |(_, p)| p.time_idx >= a.time_idx + min_frames)
+            .filter(move |(_, p)| p.freq_bin.abs_diff(a.freq_bin) <= max_freq_delta)
+    }
+
+    /// Index of the first peak inside the zone of `peaks[anchor]`, the next anchor of a quad.
+    fn next_anchor(&self, peaks: &[SpectrogramPoint], anchor: usize) -> Option<usize> {
+        self.candidates(peaks, anchor).next().map(|(i, _)| i)
+    }
+
+    /// Targets of `peaks[anchor]` in time order. `peaks` must be sorted by time.
+    fn targets<'a>(&self, peaks: &'a [SpectrogramPoint], anchor: usize) -> Vec<&'a SpectrogramPoint> {
+        let mut targets: Vec<&SpectrogramPoint> = self.candidates(peaks, anchor).map(|(_, p)| p).collect();
+
+        if targets.len() > self.fan_out {
+            targets.sort_by(|x, y| y.magnitude.total_cmp(&x.magnitude));
+            targets.truncate(self.fan_out);
+            targets.sort_by_key(|p| (p.time_idx, p.freq_bin));
+        }
+        targets
+    }
+}
+
+/// Radius each field of a pair hash is perturbed by on the query side, 0 keeps it exact.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairFuzz {
+    pub anchor_freq: u32,
+    pub target_freq: u32,
+    pub delta_time: u32,
+}
+
+impl PairFuzz {
+    fn radii(self) -> [u32; 3] {
+        [self.anchor_freq, self.target_freq, self.delta_time]
+    }
+}
+
+/// Radius each field of a quad hash is perturbed by on the query side, 0 keeps it exact.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuadFuzz {
+    /// The three anchors, then the target
+    pub freqs: [u32; 4],
+    /// dt1, dt2 and dt3
+    pub time_deltas: [u32; 3],
+}
+
+impl QuadFuzz {
+    /// The original fuzzy query: the target's frequency and time wiggled by one, nine hashes per quad.
+    pub const TARGET: QuadFuzz = QuadFuzz { freqs: [0, 0, 0, 1], time_deltas: [0, 0, 1] };
+
+    fn radii(self) -> [u32; 7] {
+        let [f1, f2, f3, f4] = self.freqs;
+        let [dt1, dt2, dt3] = self.time_deltas;
+        [f1, f2, f3, f4, dt1, dt2, dt3]
+    }
+}
+
+/// Calls `emit` with every variant of `exact` whose fields are within `radii`,
+/// and its L1 distance from `exact`. Negative values are skipped.
+fn expand<const N: usize, E: FnMut([u64; N], u32)>(exact: [u64; N], radii: [u32; N], emit: &mut E) {
+    fn walk<const N: usize, E: FnMut([u64; N], u32)>(
+        field: usize,
+        current: [u64; N],
+        exact: &[u64; N],
+        radii: &[u32; N],
+        distance: u32,
+        emit: &mut E,
+    ) {
+        if field == N {
+            emit(current, distance);
+            return;
+        }
+        let radius = radii[field] as i64;
+        for delta in -radius..=radius {
+            let value = exact[field] as i64 + delta;
+            if value < 0 {
+                continue;
+            }
+            let mut next = current;
+            next[field] = value as u64;
+            walk(field + 1, next, exact, radii, distance + delta.unsigned_abs() as u32, emit);
+        }
+    }
+    walk(0, exact, &exact, &radii, 0, emit);
+}
+
+/// Pushes the fuzzy fingerprint of every variant of `exact` that `encode` accepts.
+/// Saturating overflow clamps several variants onto the same hash, those are
+/// pushed once at their smallest distance. `seen` is scratch space for the caller to reuse
+fn push_expanded<const N: usize, H: Fn([u64; N]) -> Option<u64>>(
+    exact: [u64; N],
+    radii: [u32; N],
+    encode: H,
+    time_offset: usize,
+    seen: &mut HashMap<u64, usize>,
+    fingerprints: &mut Vec<Fingerprint>,
+) {
+    seen.clear();
+    expand(exact, radii, &mut |fields, distance| {
+        let Some(hash) = encode(fields) else { return };
+        match seen.get(&hash) {
+            Some(&i) => fingerprints[i].distance = fingerprints[i].distance.min(distance),
+            None => {
+                seen.insert(hash, fingerprints.len());
+                fingerprints.push(Fingerprint::fuzzy(hash, time_offset, distance));
+            }
+        }
+    });
+}
 
-    // Config for the target zone
-    let target_zone_size = 10; // Look 5 time steps ahead
-    let delay = 5;            // Start looking 1 step after anchor
+/// Anchor/target pairs, the landmark hash.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PairConfig {
+    /// Targets paired with each anchor
+    pub target_zone_size: usize,
+    /// Peaks skipped between the anchor and the first target
+    pub delay: usize,
+    pub resolution: HashResolution,
+    /// Handling of bins or deltas too large for their hash field
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// Bounded target zone, replaces `target_zone_size` and `delay` when set
+    #[serde(default)]
+    pub zone: Option<TargetZone>,
+    /// Query-side expansion, exact hashes only by default
+    #[serde(default)]
+    pub fuzzy: PairFuzz,
+}
 
-    for (i, anchor) in peaks.iter().enumerate() {
-        if i+delay+target_zone_size >= peaks.len() {
-            break;
+impl Default for PairConfig {
+    fn default() -> Self {
+        Self {
+            target_zone_size: 10,
+            delay: 5,
+            resolution: HashResolution::Bins,
+            overflow: OverflowPolicy::Skip,
+            zone: None,
+            fuzzy: PairFuzz::default(),
         }
-        for target in peaks[(i+delay)..(i+delay+target_zone_size)].iter() {
+    }
+}
+
+/// Three anchors and one target per hash.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuadConfig {
+    /// Targets hashed with each anchor triple
+    pub target_zone_size: usize,
+    /// Peaks skipped between the third anchor and the first target
+    pub delay: usize,
+    pub resolution: HashResolution,
+    /// Shorthand for `fuzzy: QuadFuzz::TARGET`, kept so older databases still load
+    pub fuzzy_query: bool,
+    /// Handling of bins or deltas too large for their hash field
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// Bounded zone that also picks the 2nd and 3rd anchors, replaces
+    /// `target_zone_size` and `delay` when set
+    #[serde(default)]
+    pub zone: Option<TargetZone>,
+    /// Query-side expansion, takes precedence over `fuzzy_query` when set
+    #[serde(default)]
+    pub fuzzy: QuadFuzz,
+}
+
+impl QuadConfig {
+    /// The expansion queries use, with `fuzzy_query` read as `QuadFuzz::TARGET`.
+    pub fn query_fuzz(&self) -> QuadFuzz {
+        if self.fuzzy == QuadFuzz::default() && self.fuzzy_query {
+            QuadFuzz::TARGET
+        } else {
+            self.fuzzy
+        }
+    }
+}
+
+impl Default for QuadConfig {
+    fn default() -> Self {
+        Self {
+            target_zone_size: 5,
+            delay: 3,
+            resolution: HashResolution::Bins,
+            fuzzy_query: false,
+            overflow: OverflowPolicy::Skip,
+            zone: None,
+            fuzzy: QuadFuzz::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PairFingerprinter {
+    config: PairConfig,
+}
+
+impl PairFingerprinter {
+    /// Calls `emit` with the fields of every pair: anchor frequency, target frequency, delta time.
+    fn for_each_pair<E: FnMut(&SpectrogramPoint, [u64; 3])>(&self, peaks: &[SpectrogramPoint], mut emit: E) {
+        let PairConfig { target_zone_size, delay, resolution, zone, .. } = self.config;
+
+        for (i, anchor) in peaks.iter().enumerate() {
             // Create a Hash: [Anchor Freq | Target Freq | Delta Time]
-            // We use simple bit-shifting for this example, but you can use a real hasher.
-            let f1 = anchor.freq_bin as u64;
-            let f2 = target.freq_bin as u64;
-            let dt = (target.time_idx - anchor.time_idx) as u64;
+            let pair = |target: &SpectrogramPoint| {
+                emit(anchor, [resolution.freq(anchor), resolution.freq(target), resolution.time_delta(anchor, target)]);
+            };
+            match zone {
+                Some(zone) => zone.targets(peaks, i).into_iter().for_each(pair),
+                None => {
+                    if i+delay+target_zone_size >= peaks.len() {
+                        break;
+                    }
+                    peaks[(i+delay)..(i+delay+target_zone_size)].iter().for_each(pair);
+                }
+            }
+        }
+    }
+}
+
+impl Fingerprinter for PairFingerprinter {
+    type Config = PairConfig;
 
-            // Example packing: 20 bits f1, 20 bits f2, 24 bits dt
-            let hash = (f1 << 44) | (f2 << 24) | dt;
+    fn with_config(config: PairConfig) -> Result<Self, FingerprintConfigError> {
+        config.resolution.validate()?;
+        Ok(Self { config })
+    }
 
-            fingerprints.push(Fingerprint {
-                hash,
-                time_offset: anchor.time_idx,
-            });
+    fn config(&self) -> &PairConfig {
+        &self.config
+    }
+
+    fn index(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        let mut fingerprints = Vec::new();
+        let overflow = self.config.overflow;
+
+        self.for_each_pair(peaks, |anchor, [f1, f2, dt]| {
+            if let Some(hash) = PairHash::new(f1, f2, dt).encode_with(overflow) {
+                fingerprints.push(Fingerprint::new(hash, anchor.time_idx));
+            }
+        });
+        fingerprints
+    }
+
+    fn query(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        if self.config.fuzzy == PairFuzz::default() {
+            return self.index(peaks);
         }
+
+        let mut fingerprints = Vec::new();
+        let mut seen = HashMap::new();
+        let PairConfig { overflow, fuzzy, .. } = self.config;
+
+        self.for_each_pair(peaks, |anchor, exact| {
+            let encode = |[f1, f2, dt]: [u64; 3]| PairHash::new(f1, f2, dt).encode_with(overflow);
+            push_expanded(exact, fuzzy.radii(), encode, anchor.time_idx, &mut seen, &mut fingerprints);
+        });
+        fingerprints
     }
-    fingerprints
 }
-pub fn generate_fingerprints_quad(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
-    let mut fingerprints = Vec::new();
 
-    // Config for the target zone
-    let target_zone_size = 5; // Look 5 peaks ahead
-    let delay = 3;            // Start looking 3 peaks after the 3rd anchor
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuadFingerprinter {
+    config: QuadConfig,
+}
+
+impl QuadFingerprinter {
+    /// Calls `emit` with the first anchor and the fields of every quad:
+    /// four frequencies, then the three time deltas from the first anchor.
+    fn for_each_quad<E>(&self, peaks: &[SpectrogramPoint], mut emit: E)
+    where
+        E: FnMut(&SpectrogramPoint, [u64; 7]),
+    {
+        let QuadConfig { target_zone_size, delay, zone, resolution, .. } = self.config;
+
+        for i in 0..peaks.len() {
+            let mut quad = |a2: &SpectrogramPoint, a3: &SpectrogramPoint, target: &SpectrogramPoint| {
+                // Time deltas are relative to the FIRST anchor (a1), which is also the reference time
+                let a1 = &peaks[i];
+                let [f1, f2, f3, f4] = [a1, a2, a3, target].map(|p| resolution.freq(p));
+                let [dt1, dt2, dt3] = [a2, a3, target].map(|p| resolution.time_delta(a1, p));
+                emit(a1, [f1, f2, f3, f4, dt1, dt2, dt3]);
+            };
 
-    // We iterate up to the point where we can safely fit 3 anchors + delay + target zone
-    for i in 0..peaks.len() {
-        if i + 2 + delay + target_zone_size >= peaks.len() {
-            break;
+            match zone {
+                Some(zone) => {
+                    // Each anchor is the first peak in the zone of the one before,
+                    // the targets are measured from the 3rd anchor
+                    let anchors = zone.next_anchor(peaks, i)
+                        .and_then(|j| zone.next_anchor(peaks, j).map(|k| (j, k)));
+                    if let Some((j, k)) = anchors {
+                        for target in zone.targets(peaks, k) {
+                            quad(&peaks[j], &peaks[k], target);
+                        }
+                    }
+                }
+                None => {
+                    // We iterate up to the point where we can safely fit 3 anchors + delay + target zone
+                    if i + 2 + delay + target_zone_size >= peaks.len() {
+                        break;
+                    }
+
+                    // Target zone starts after the 3rd anchor + delay
+                    let target_start = i + 2 + delay;
+                    let target_end = target_start + target_zone_size;
+                    for target in &peaks[target_start..target_end] {
+                        quad(&peaks[i + 1], &peaks[i + 2], target);
+                    }
+                }
+            }
         }
+    }
+}
 
-        // Define our 3 anchor points
-        let a1 = &peaks[i];
-        let a2 = &peaks[i + 1];
-        let a3 = &peaks[i + 2];
+fn quad_hash([f1, f2, f3, f4, dt1, dt2, dt3]: [u64; 7]) -> QuadHash {
+    QuadHash::new([f1, f2, f3, f4], [dt1, dt2, dt3])
+}
 
-        // Target zone starts after the 3rd anchor + delay
-        let target_start = i + 2 + delay;
-        let target_end = target_start + target_zone_size;
+impl Fingerprinter for QuadFingerprinter {
+    type Config = QuadConfig;
 
-        for target in &peaks[target_start..target_end] {
-            // 1. Mask frequencies to 9 bits (max value 511)
-            let f1 = (a1.freq_bin as u64) & 0x1FF;
-            let f2 = (a2.freq_bin as u64) & 0x1FF;
-            let f3 = (a3.freq_bin as u64) & 0x1FF;
-            let f4 = (target.freq_bin as u64) & 0x1FF;
+    fn with_config(config: QuadConfig) -> Result<Self, FingerprintConfigError> {
+        config.resolution.validate()?;
+        Ok(Self { config })
+    }
 
-            // 2. Calculate time deltas relative to the FIRST anchor (a1)
-            // Using saturating_sub ensures we don't underflow if time indices are out of order
-            let dt1 = (a2.time_idx.saturating_sub(a1.time_idx) as u64) & 0x1FF;     // 9 bits
-            let dt2 = (a3.time_idx.saturating_sub(a1.time_idx) as u64) & 0x1FF;     // 9 bits
-            let dt3 = (target.time_idx.saturating_sub(a1.time_idx) as u64) & 0x3FF; // 10 bits
+    fn config(&self) -> &QuadConfig {
+        &self.config
+    }
 
-            // 3. Pack into a single 64-bit integer
-            // Layout: [f1: 9][f2: 9][f3: 9][f4: 9][dt1: 9][dt2: 9][dt3: 10]
-            let hash = (f1 << 55) |
-                (f2 << 46) |
-                (f3 << 37) |
-                (f4 << 28) |
-                (dt1 << 19) |
-                (dt2 << 10) |
-                dt3;
+    fn index(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        let mut fingerprints = Vec::new();
+        let overflow = self.config.overflow;
 
-            fingerprints.push(Fingerprint {
-                hash,
-                time_offset: a1.time_idx, // Use the first anchor as the reference time
-            });
+        self.for_each_quad(peaks, |a1, fields| {
+            if let Some(hash) = quad_hash(fields).encode_with(overflow) {
+                fingerprints.push(Fingerprint::new(hash, a1.time_idx));
+            }
+        });
+        fingerprints
+    }
+
+    // We only expand hashes when we are QUERYING the database (listening to the mic).
+    // When indexing a song into the DB, we only save the exact hash.
+    fn query(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        let fuzzy = self.config.query_fuzz();
+        if fuzzy == QuadFuzz::default() {
+            return self.index(peaks);
         }
+
+        let mut fingerprints = Vec::new();
+        let mut seen = HashMap::new();
+        let overflow = self.config.overflow;
+
+        self.for_each_quad(peaks, |a1, exact| {
+            let encode = |fields| quad_hash(fields).encode_with(overflow);
+            push_expanded(exact, fuzzy.radii(), encode, a1.time_idx, &mut seen, &mut fingerprints);
+        });
+        fingerprints
     }
+}
 
-    fingerprints
+/// Settings of the tempo and pitch invariant quads.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InvariantQuadConfig {
+    /// Where the far corner of a box may sit relative to its root peak.
+    /// `fan_out` is the number of boxes per root
+    pub zone: TargetZone,
+    /// Quantisation levels per normalised coordinate, at most 4096.
+    /// Fewer levels tolerate more distortion
+    pub levels: u32,
+    /// Width in octaves of the buckets the box's height to width ratio is sorted into.
+    /// Queries also look up the nearer neighbouring bucket, so a ratio changed by
+    /// less than half a bucket by a tempo or pitch change still matches
+    pub aspect_tolerance: f32,
+    pub overflow: OverflowPolicy,
 }
-// We modify our fingerprint generation function to ONLY expand 
-// hashes when we are QUERYING the database (listening to the mic).
-// When indexing a song into the DB, we only save the exact hash.
 
-pub fn generate_fuzzy_query_hashes(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
-    let mut fingerprints = Vec::new();
-    let target_zone_size = 5;
-    let delay = 3;
-
-    for i in 0..peaks.len() {
-        if i + 2 + delay + target_zone_size >= peaks.len() { break; }
-
-        let a1 = &peaks[i];
-        let a2 = &peaks[i + 1];
-        let a3 = &peaks[i + 2];
-        let target_start = i + 2 + delay;
-        let target_end = target_start + target_zone_size;
-
-        for target in &peaks[target_start..target_end] {
-            let f1 = (a1.freq_bin as u64) & 0x1FF;
-            let f2 = (a2.freq_bin as u64) & 0x1FF;
-            let f3 = (a3.freq_bin as u64) & 0x1FF;
-
-            let dt1 = (a2.time_idx.saturating_sub(a1.time_idx) as u64) & 0x1FF;
-            let dt2 = (a3.time_idx.saturating_sub(a1.time_idx) as u64) & 0x1FF;
-
-            // The exact values
-            let exact_f4 = target.freq_bin as i64;
-            let exact_dt3 = target.time_idx.saturating_sub(a1.time_idx) as i64;
-
-            // "Wiggle" the target frequency and time delta by -1, 0, and +1
-            // This generates 9 total variations per quad (3x3 grid)
-            for f4_fuzz in [-1, 0, 1] {
-                for dt3_fuzz in [-1, 0, 1] {
-
-                    let f4_fuzzed = (exact_f4 + f4_fuzz).max(0) as u64 & 0x1FF;
-                    let dt3_fuzzed = (exact_dt3 + dt3_fuzz).max(0) as u64 & 0x3FF;
-
-                    let fuzzy_hash = (f1 << 55) |
-                        (f2 << 46) |
-                        (f3 << 37) |
-                        (f4_fuzzed << 28) |
-                        (dt1 << 19) |
-                        (dt2 << 10) |
-                        dt3_fuzzed;
-
-                    fingerprints.push(Fingerprint {
-                        hash: fuzzy_hash,
-                        time_offset: a1.time_idx,
-                    });
+impl Default for InvariantQuadConfig {
+    /// Aspect buckets of half an octave absorb tempo and pitch changes of about 19%.
+    fn default() -> Self {
+        Self {
+            zone: TargetZone { min_frames: 4, max_frames: 60, max_freq_delta: 200, fan_out: 3 },
+            levels: 32,
+            aspect_tolerance: 0.5,
+            overflow: OverflowPolicy::Skip,
+        }
+    }
+}
+
+// Aspect bucket of a square box, so flat and tall boxes both stay positive
+const ASPECT_ZERO: i64 = 1 << 15;
+
+/// Quads in the spirit of Sonnleitner & Widmer: a root peak A and a far corner B
+/// span a box, and the hash holds the two loudest peaks C and D inside it in
+/// coordinates relative to that box, along with the coarse shape of the box.
+///
+/// Stretching time or scaling frequency stretches the box with its content, so
+/// the hash survives speed-ups and pitch changes within the aspect tolerance. Use
+/// `AudioDatabase::find_best_match_stretched` to also recover the stretch factor.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InvariantQuadFingerprinter {
+    config: InvariantQuadConfig,
+}
+
+impl InvariantQuadFingerprinter {
+    /// Level of `value` between `from` and `to`.
+    fn level(&self, value: f32, from: f32, to: f32) -> u64 {
+        let levels = self.config.levels.max(1);
+        let normalised = ((value - from) / (to - from)).clamp(0.0, 1.0);
+        ((normalised * levels as f32) as u32).min(levels - 1) as u64
+    }
+
+    /// Aspect bucket of a box, as a fractional bucket number.
+    fn aspect_bucket(&self, height: f32, width: f32) -> f32 {
+        (height / width).log2() / self.config.aspect_tolerance
+    }
+
+    fn aspect_level(bucket: f32) -> u64 {
+        (bucket.floor() as i64 + ASPECT_ZERO).max(0) as u64
+    }
+
+    /// Calls `emit` with the root of every box, the levels of its inner peaks and its aspect bucket.
+    fn for_each_box<E: FnMut(&SpectrogramPoint, [u64; 4], f32)>(&self, peaks: &[SpectrogramPoint], mut emit: E) {
+        for (i, a) in peaks.iter().enumerate() {
+            for b in self.config.zone.targets(peaks, i) {
+                let (f_low, f_high) = (a.freq_pos.min(b.freq_pos), a.freq_pos.max(b.freq_pos));
+                let width = b.time_pos - a.time_pos;
+                if width <= 0.0 {
+                    continue;
                 }
+
+                // 1. Peaks strictly inside the box, in frequency
+                let mut inner: Vec<&SpectrogramPoint> = peaks[i + 1..]
+                    .iter()
+                    .take_while(|p| p.time_pos <= b.time_pos)
+                    .filter(|p| !std::ptr::eq(*p, b) && p.freq_pos > f_low && p.freq_pos < f_high)
+                    .collect();
+                if inner.len() < 2 {
+                    continue;
+                }
+
+                // 2. The two loudest become C and D, in time order
+                inner.sort_by(|x, y| y.magnitude.total_cmp(&x.magnitude));
+                let (mut c, mut d) = (inner[0], inner[1]);
+                if (d.time_pos, d.freq_pos) < (c.time_pos, c.freq_pos) {
+                    std::mem::swap(&mut c, &mut d);
+                }
+
+                // 3. Normalise to the box, A is (0, 0) and B is (1, 1)
+                let coords = [
+                    self.level(c.time_pos, a.time_pos, b.time_pos),
+                    self.level(c.freq_pos, a.freq_pos, b.freq_pos),
+                    self.level(d.time_pos, a.time_pos, b.time_pos),
+                    self.level(d.freq_pos, a.freq_pos, b.freq_pos),
+                ];
+                emit(a, coords, self.aspect_bucket(f_high - f_low, width));
             }
         }
     }
-    fingerprints
-}
\ No newline at end of file
+}
+
+impl Fingerprinter for InvariantQuadFingerprinter {
+    type Config = InvariantQuadConfig;
+
+    fn with_config(config: InvariantQuadConfig) -> Result<Self, FingerprintConfigError> {
+        let max = 1 << InvariantQuadHash::COORD_BITS;
+        if config.levels == 0 || config.levels > max {
+            return Err(FingerprintConfigError::LevelsOutOfRange { levels: config.levels, max });
+        }
+        let step = config.aspect_tolerance;
+        if !step.is_finite() || step <= 0.0 {
+            return Err(FingerprintConfigError::InvalidStep { field: "aspect_tolerance", step });
+        }
+        Ok(Self { config })
+    }
+
+    fn config(&self) -> &InvariantQuadConfig {
+        &self.config
+    }
+
+    fn index(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        let mut fingerprints = Vec::new();
+        let overflow = self.config.overflow;
+
+        self.for_each_box(peaks, |a, coords, bucket| {
+            let hash = InvariantQuadHash::new(coords, Self::aspect_level(bucket));
+            if let Some(hash) = hash.encode_with(overflow) {
+                fingerprints.push(Fingerprint::new(hash, a.time_idx));
+            }
+        });
+        fingerprints
+    }
+
+    // A stretched query can push the aspect across a bucket edge, so the bucket
+    // on the nearer side is looked up too, weighted like a fuzzy hit
+    fn query(&self, peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+        let mut fingerprints = Vec::new();
+        let overflow = self.config.overflow;
+
+        self.for_each_box(peaks, |a, coords, bucket| {
+            let neighbour = if bucket - bucket.floor() < 0.5 { bucket - 1.0 } else { bucket + 1.0 };
+            let encode = |bucket| InvariantQuadHash::new(coords, Self::aspect_level(bucket)).encode_with(overflow);
+            let exact = encode(bucket);
+            if let Some(hash) = exact {
+                fingerprints.push(Fingerprint::new(hash, a.time_idx));
+            }
+            // Extreme aspects clamp both buckets onto the same hash
+            match encode(neighbour) {
+                Some(hash) if Some(hash) != exact => fingerprints.push(Fingerprint::fuzzy(hash, a.time_idx, 1)),
+                _ => {}
+            }
+        });
+        fingerprints
+    }
+}
+
+pub fn generate_fingerprints(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+    PairFingerprinter::default().index(peaks)
+}
+
+pub fn generate_fingerprints_with_resolution(
+    peaks: &[SpectrogramPoint],
+    resolution: HashResolution,
+) -> Result<Vec<Fingerprint>, FingerprintConfigError> {
+    Ok(PairFingerprinter::with_config(PairConfig { resolution, ..PairConfig::default() })?.index(peaks))
+}
+
+pub fn generate_fingerprints_quad(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+    QuadFingerprinter::default().index(peaks)
+}
+
+pub fn generate_fingerprints_quad_with_resolution(
+    peaks: &[SpectrogramPoint],
+    resolution: HashResolution,
+) -> Result<Vec<Fingerprint>, FingerprintConfigError> {
+    Ok(QuadFingerprinter::with_config(QuadConfig { resolution, ..QuadConfig::default() })?.index(peaks))
+}
+
+/// Quad hashes with the target wiggled by one bin and one frame, for the query side.
+pub fn generate_fuzzy_query_hashes(peaks: &[SpectrogramPoint]) -> Vec<Fingerprint> {
+    QuadFingerprinter { config: QuadConfig { fuzzy_query: true, ..QuadConfig::default() } }.query(peaks)
+}
@@ -0,0 +1,382 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+/// A field value that doesn't fit its bits in a packed hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HashOverflow {
+    pub field: &'static str,
+    pub value: u64,
+    /// Largest value the field can hold
+    pub max: u64,
+}
+
+impl fmt::Display for HashOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hash field {} is {}, the maximum is {}", self.field, self.value, self.max)
+    }
+}
+
+impl std::error::Error for HashOverflow {}
+
+/// What a fingerprinter does with a hash whose fields don't fit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Drop the hash
+    #[default]
+    Skip,
+    /// Clamp each field to its maximum
+    Saturate,
+}
+
+fn max_of(bits: u32) -> u64 {
+    (1u64 << bits) - 1
+}
+
+fn check(field: &'static str, value: u64, bits: u32) -> Result<u64, HashOverflow> {
+    let max = max_of(bits);
+    if value > max { Err(HashOverflow { field, value, max }) } else { Ok(value) }
+}
+
+/// Anchor/target landmark hash.
+///
+/// Layout: [anchor_freq: 20][target_freq: 20][delta_time: 24]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PairHash {
+    anchor_freq: u64,
+    target_freq: u64,
+    delta_time: u64,
+}
+
+impl PairHash {
+    pub const FREQ_BITS: u32 = 20;
+    pub const TIME_BITS: u32 = 24;
+
+    pub fn new(anchor_freq: u64, target_freq: u64, delta_time: u64) -> Self {
+        Self { anchor_freq, target_freq, delta_time }
+    }
+
+    pub fn anchor_freq(&self) -> u64 {
+        self.anchor_freq
+    }
+
+    pub fn target_freq(&self) -> u64 {
+        self.target_freq
+    }
+
+    pub fn delta_time(&self) -> u64 {
+        self.delta_time
+    }
+
+    /// Packs the fields, failing on the first one that doesn't fit.
+    pub fn encode(&self) -> Result<u64, HashOverflow> {
+        let f1 = check("anchor_freq", self.anchor_freq, Self::FREQ_BITS)?;
+        let f2 = check("target_freq", self.target_freq, Self::FREQ_BITS)?;
+        let dt = check("delta_time", self.delta_time, Self::TIME_BITS)?;
+        Ok((f1 << 44) | (f2 << 24) | dt)
+    }
+
+    /// Packs the fields, clamping each one to its maximum.
+    pub fn encode_saturating(&self) -> u64 {
+        let f1 = self.anchor_freq.min(max_of(Self::FREQ_BITS));
+        let f2 = self.target_freq.min(max_of(Self::FREQ_BITS));
+        let dt = self.delta_time.min(max_of(Self::TIME_BITS));
+        (f1 << 44) | (f2 << 24) | dt
+    }
+
+    /// Packs under `policy`, `None` when the hash is to be skipped.
+    pub fn encode_with(&self, policy: OverflowPolicy) -> Option<u64> {
+        match policy {
+            OverflowPolicy::Skip => self.encode().ok(),
+            OverflowPolicy::Saturate => Some(self.encode_saturating()),
+        }
+    }
+
+    pub fn decode(hash: u64) -> Self {
+        Self {
+            anchor_freq: (hash >> 44) & max_of(Self::FREQ_BITS),
+            target_freq: (hash >> 24) & max_of(Self::FREQ_BITS),
+            delta_time: hash & max_of(Self::TIME_BITS),
+        }
+    }
+}
+
+/// Three anchors and a target, times relative to the first anchor.
+///
+/// Layout: [f1: 9][f2: 9][f3: 9][f4: 9][dt1: 9][dt2: 9][dt3: 10]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QuadHash {
+    freqs: [u64; 4],
+    time_deltas: [u64; 3],
+}
+
+impl QuadHash {
+    pub const FREQ_BITS: u32 = 9;
+    /// Bits of dt1, dt2 and dt3
+    pub const TIME_BITS: [u32; 3] = [9, 9, 10];
+
+    const FREQ_FIELDS: [&'static str; 4] = ["f1", "f2", "f3", "f4"];
+    const TIME_FIELDS: [&'static str; 3] = ["dt1", "dt2", "dt3"];
+
+    /// `freqs` are the three anchors then the target, `time_deltas` run from
+    /// the first anchor to the second, the third and the target.
+    pub fn new(freqs: [u64; 4], time_deltas: [u64; 3]) -> Self {
+        Self { freqs, time_deltas }
+    }
+
+    pub fn freqs(&self) -> [u64; 4] {
+        self.freqs
+    }
+
+    pub fn time_deltas(&self) -> [u64; 3] {
+        self.time_deltas
+    }
+
+    /// Packs the fields, failing on the first one that doesn't fit.
+    pub fn encode(&self) -> Result<u64, HashOverflow> {
+        let mut hash = 0u64;
+        for (field, freq) in Self::FREQ_FIELDS.iter().zip(self.freqs) {
+            hash = (hash << Self::FREQ_BITS) | check(field, freq, Self::FREQ_BITS)?;
+        }
+        for ((field, dt), bits) in Self::TIME_FIELDS.iter().zip(self.time_deltas).zip(Self::TIME_BITS) {
+            hash = (hash << bits) | check(field, dt, bits)?;
+        }
+        Ok(hash)
+    }
+
+    /// Packs the fields, clamping each one to its maximum.
+    pub fn encode_saturating(&self) -> u64 {
+        let mut hash = 0u64;
+        for freq in self.freqs {
+            hash = (hash << Self::FREQ_BITS) | freq.min(max_of(Self::FREQ_BITS));
+        }
+        for (dt, bits) in self.time_deltas.into_iter().zip(Self::TIME_BITS) {
+            hash = (hash << bits) | dt.min(max_of(bits));
+        }
+        hash
+    }
+
+    /// Packs under `policy`, `None` when the hash is to be skipped.
+    pub fn encode_with(&self, policy: OverflowPolicy) -> Option<u64> {
+        match policy {
+            OverflowPolicy::Skip => self.encode().ok(),
+            OverflowPolicy::Saturate => Some(self.encode_saturating()),
+        }
+    }
+
+    pub fn decode(hash: u64) -> Self {
+        // Fields come off the low end, last field first
+        let mut hash = hash;
+        let mut time_deltas = [0u64; 3];
+        for i in (0..3).rev() {
+            time_deltas[i] = hash & max_of(Self::TIME_BITS[i]);
+            hash >>= Self::TIME_BITS[i];
+        }
+        let mut freqs = [0u64; 4];
+        for i in (0..4).rev() {
+            freqs[i] = hash & max_of(Self::FREQ_BITS);
+            hash >>= Self::FREQ_BITS;
+        }
+        Self { freqs, time_deltas }
+    }
+}
+
+/// Positions of the two inner peaks of a quad, normalised to the box spanned
+/// by the root and the far corner, plus the coarse shape of that box.
+///
+/// Layout: [cx: 12][cy: 12][dx: 12][dy: 12][aspect: 16], the coords are quantisation levels
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InvariantQuadHash {
+    coords: [u64; 4],
+    aspect: u64,
+}
+
+impl InvariantQuadHash {
+    pub const COORD_BITS: u32 = 12;
+    pub const ASPECT_BITS: u32 = 16;
+
+    const FIELDS: [&'static str; 4] = ["cx", "cy", "dx", "dy"];
+
+    /// `coords` are the time and frequency levels of the first, then the second inner peak.
+    /// `aspect` is the quantised height to width ratio of the box.
+    pub fn new(coords: [u64; 4], aspect: u64) -> Self {
+        Self { coords, aspect }
+    }
+
+    pub fn coords(&self) -> [u64; 4] {
+        self.coords
+    }
+
+    pub fn aspect(&self) -> u64 {
+        self.aspect
+    }
+
+    /// Packs the fields, failing on the first one that doesn't fit.
+    pub fn encode(&self) -> Result<u64, HashOverflow> {
+        let mut hash = 0u64;
+        for (field, coord) in Self::FIELDS.iter().zip(self.coords) {
+            hash = (hash << Self::COORD_BITS) | check(field, coord, Self::COORD_BITS)?;
+        }
+        Ok((hash << Self::ASPECT_BITS) | check("aspect", self.aspect, Self::ASPECT_BITS)?)
+    }
+
+    /// Packs the fields, clamping each one to its maximum.
+    pub fn encode_saturating(&self) -> u64 {
+        let hash = self.coords
+            .iter()
+            .fold(0u64, |hash, coord| (hash << Self::COORD_BITS) | (*coord).min(max_of(Self::COORD_BITS)));
+        (hash << Self::ASPECT_BITS) | self.aspect.min(max_of(Self::ASPECT_BITS))
+    }
+
+    /// Packs under `policy`, `None` when the hash is to be skipped.
+    pub fn encode_with(&self, policy: OverflowPolicy) -> Option<u64> {
+        match policy {
+            OverflowPolicy::Skip => self.encode().ok(),
+            OverflowPolicy::Saturate => Some(self.encode_saturating()),
+        }
+    }
+
+    pub fn decode(hash: u64) -> Self {
+        let aspect = hash & max_of(Self::ASPECT_BITS);
+        let mut coords = [0u64; 4];
+        for (i, coord) in coords.iter_mut().enumerate() {
+            let shift = Self::ASPECT_BITS + Self::COORD_BITS * (3 - i as u32);
+            *coord = (hash >> shift) & max_of(Self::COORD_BITS);
+        }
+        Self { coords, aspect }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic values in `0..=max`, enough to cover the fields without a rand dependency.
+    fn randoms(seed: u64, max: u64, count: usize) -> Vec<u64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                (state >> 11) % (max + 1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pair_hash_round_trips() {
+        let (freq_max, time_max) = (max_of(PairHash::FREQ_BITS), max_of(PairHash::TIME_BITS));
+        let mut cases = vec![PairHash::new(0, 0, 0), PairHash::new(freq_max, freq_max, time_max)];
+        for ((f1, f2), dt) in randoms(1, freq_max, 100).into_iter().zip(randoms(2, freq_max, 100)).zip(randoms(3, time_max, 100)) {
+            cases.push(PairHash::new(f1, f2, dt));
+        }
+
+        for hash in cases {
+            assert_eq!(PairHash::decode(hash.encode().unwrap()), hash);
+            assert_eq!(PairHash::decode(hash.encode_saturating()), hash);
+        }
+    }
+
+    #[test]
+    fn pair_hash_overflow_names_the_field() {
+        let (freq_max, time_max) = (max_of(PairHash::FREQ_BITS), max_of(PairHash::TIME_BITS));
+        let cases = [
+            (PairHash::new(freq_max + 1, 0, 0), "anchor_freq", freq_max),
+            (PairHash::new(0, freq_max + 1, 0), "target_freq", freq_max),
+            (PairHash::new(0, 0, time_max + 1), "delta_time", time_max),
+        ];
+        for (hash, field, max) in cases {
+            assert_eq!(hash.encode(), Err(HashOverflow { field, value: max + 1, max }));
+            assert_eq!(hash.encode_with(OverflowPolicy::Skip), None);
+        }
+    }
+
+    #[test]
+    fn pair_hash_saturates_to_max() {
+        let (freq_max, time_max) = (max_of(PairHash::FREQ_BITS), max_of(PairHash::TIME_BITS));
+        let hash = PairHash::new(freq_max + 1, u64::MAX, time_max + 1);
+        let clamped = PairHash::decode(hash.encode_saturating());
+        assert_eq!(clamped, PairHash::new(freq_max, freq_max, time_max));
+        assert_eq!(hash.encode_with(OverflowPolicy::Saturate), Some(clamped.encode().unwrap()));
+    }
+
+    #[test]
+    fn quad_hash_round_trips() {
+        let freq_max = max_of(QuadHash::FREQ_BITS);
+        let time_max = QuadHash::TIME_BITS.map(max_of);
+        let mut cases = vec![QuadHash::new([0; 4], [0; 3]), QuadHash::new([freq_max; 4], time_max)];
+        let freqs = randoms(4, freq_max, 400);
+        let times: Vec<Vec<u64>> = time_max.iter().zip(5..).map(|(&max, seed)| randoms(seed, max, 100)).collect();
+        for i in 0..100 {
+            let f = &freqs[4 * i..4 * i + 4];
+            cases.push(QuadHash::new([f[0], f[1], f[2], f[3]], [times[0][i], times[1][i], times[2][i]]));
+        }
+
+        for hash in cases {
+            assert_eq!(QuadHash::decode(hash.encode().unwrap()), hash);
+            assert_eq!(QuadHash::decode(hash.encode_saturating()), hash);
+        }
+    }
+
+    #[test]
+    fn quad_hash_overflow_names_the_field() {
+        let freq_max = max_of(QuadHash::FREQ_BITS);
+        for (i, field) in QuadHash::FREQ_FIELDS.into_iter().enumerate() {
+            let mut freqs = [0; 4];
+            freqs[i] = freq_max + 1;
+            let hash = QuadHash::new(freqs, [0; 3]);
+            assert_eq!(hash.encode(), Err(HashOverflow { field, value: freq_max + 1, max: freq_max }));
+        }
+        for (i, field) in QuadHash::TIME_FIELDS.into_iter().enumerate() {
+            let max = max_of(QuadHash::TIME_BITS[i]);
+            let mut time_deltas = [0; 3];
+            time_deltas[i] = max + 1;
+            let hash = QuadHash::new([0; 4], time_deltas);
+            assert_eq!(hash.encode(), Err(HashOverflow { field, value: max + 1, max }));
+        }
+    }
+
+    #[test]
+    fn quad_hash_saturates_to_max() {
+        let freq_max = max_of(QuadHash::FREQ_BITS);
+        let time_max = QuadHash::TIME_BITS.map(max_of);
+        let hash = QuadHash::new([freq_max + 1, u64::MAX, freq_max + 7, 1 << 40], time_max.map(|max| max + 1));
+        let clamped = QuadHash::decode(hash.encode_saturating());
+        assert_eq!(clamped, QuadHash::new([freq_max; 4], time_max));
+    }
+
+    #[test]
+    fn invariant_quad_hash_round_trips() {
+        let (max, aspect_max) = (max_of(InvariantQuadHash::COORD_BITS), max_of(InvariantQuadHash::ASPECT_BITS));
+        let mut cases = vec![InvariantQuadHash::new([0; 4], 0), InvariantQuadHash::new([max; 4], aspect_max)];
+        let coords = randoms(8, max, 400);
+        for (c, aspect) in coords.chunks_exact(4).zip(randoms(9, aspect_max, 100)) {
+            cases.push(InvariantQuadHash::new([c[0], c[1], c[2], c[3]], aspect));
+        }
+
+        for hash in cases {
+            assert_eq!(InvariantQuadHash::decode(hash.encode().unwrap()), hash);
+            assert_eq!(InvariantQuadHash::decode(hash.encode_saturating()), hash);
+        }
+    }
+
+    #[test]
+    fn invariant_quad_hash_overflow_names_the_field() {
+        let max = max_of(InvariantQuadHash::COORD_BITS);
+        for (i, field) in InvariantQuadHash::FIELDS.into_iter().enumerate() {
+            let mut coords = [0; 4];
+            coords[i] = max + 1;
+            let hash = InvariantQuadHash::new(coords, 0);
+            assert_eq!(hash.encode(), Err(HashOverflow { field, value: max + 1, max }));
+        }
+        let aspect_max = max_of(InvariantQuadHash::ASPECT_BITS);
+        let hash = InvariantQuadHash::new([0; 4], aspect_max + 1);
+        assert_eq!(hash.encode(), Err(HashOverflow { field: "aspect", value: aspect_max + 1, max: aspect_max }));
+    }
+
+    #[test]
+    fn invariant_quad_hash_saturates_to_max() {
+        let (max, aspect_max) = (max_of(InvariantQuadHash::COORD_BITS), max_of(InvariantQuadHash::ASPECT_BITS));
+        let hash = InvariantQuadHash::new([max + 1, u64::MAX, max, 0], aspect_max + 1);
+        let clamped = InvariantQuadHash::new([max, max, max, 0], aspect_max);
+        assert_eq!(InvariantQuadHash::decode(hash.encode_saturating()), clamped);
+    }
+}
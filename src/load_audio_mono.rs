@@ -1,60 +1,985 @@
-use symphonia::core::codecs::{DecoderOptions};
+use symphonia::core::codecs::{
+    CodecType, Decoder, DecoderOptions, CODEC_TYPE_AAC, CODEC_TYPE_ADPCM_IMA_WAV, CODEC_TYPE_ALAC,
+    CODEC_TYPE_FLAC, CODEC_TYPE_MP1, CODEC_TYPE_MP2, CODEC_TYPE_MP3, CODEC_TYPE_NULL,
+    CODEC_TYPE_PCM_S16BE, CODEC_TYPE_PCM_S16LE, CODEC_TYPE_VORBIS,
+};
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
 use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+use std::fmt;
 use std::fs::File;
-use std::path::Path;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use crate::create_spectogram::SpectrogramConfigError;
+use crate::downsampler::DownsampleError;
+use crate::generate_fingerprints::FingerprintConfigError;
 
-pub fn load_audio_mono(path: &str) -> (Vec<f32>, u32) {
-    let src = File::open(Path::new(path)).expect("failed to open media");
-    extract_audio(src)
+/// Mono samples produced by the loader.
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    /// Packets that symphonia reported as corrupt and that were skipped.
+    pub failed_packets: usize,
+    pub metadata: AudioMetadata,
 }
-pub fn load_audio_from_path(path: &Path) -> (Vec<f32>, u32){
-    let src = File::open(path).expect("failed to open media");
-    extract_audio(src)
+
+/// Tags and technical details of a source file, stored with each indexed song.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AudioMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub isrc: Option<String>,
+    pub track_number: Option<u32>,
+    /// Short codec name, e.g. "mp3" or "flac"
+    pub codec: Option<String>,
+    /// Rate of the source before any resampling
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub bits_per_sample: Option<u32>,
+    pub duration: Option<Duration>,
 }
-pub fn extract_audio(file: File) -> (Vec<f32>, u32) {
-    let mss = MediaSourceStream::new(Box::new(file), Default::default());
 
-    let probed = symphonia::default::get_probe()
-        .format(&Hint::new(), mss, &FormatOptions::default(), &MetadataOptions::default())
-        .expect("unsupported format");
+impl AudioMetadata {
+    /// Fills in the tags we care about from a metadata revision.
+    /// Fields that are already set are kept, so the first source read wins.
+    fn read_tags(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => { self.title.get_or_insert(value); }
+                Some(StandardTagKey::Artist) => { self.artist.get_or_insert(value); }
+                Some(StandardTagKey::Album) => { self.album.get_or_insert(value); }
+                Some(StandardTagKey::IdentIsrc) => { self.isrc.get_or_insert(value); }
+                // ID3 stores "3/12", keep the track part only
+                Some(StandardTagKey::TrackNumber) if self.track_number.is_none() => {
+                    self.track_number = value.split('/').next().and_then(|n| n.trim().parse().ok());
+                }
+                _ => {}
+            }
+        }
+    }
+}
 
-    let mut format = probed.format;
-    let track = format.default_track().unwrap();
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions::default())
-        .expect("unsupported codec");
+/// Controls which part of a file the loader decodes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LoadOptions {
+    /// Where to start decoding, `None` for the beginning of the file
+    pub start: Option<Duration>,
+    /// How much audio to decode from `start`, `None` for the rest of the file
+    pub duration: Option<Duration>,
+    pub channel_mode: ChannelMode,
+    pub track: TrackSelector,
+}
 
-    let track_id = track.id;
-    let mut samples: Vec<f32> = Vec::new();
-    let mut sample_rate = 0u32;
-    while let Ok(packet) = format.next_packet() {
-        if packet.track_id() != track_id { continue; }
+/// How the decoded channels are folded into the single channel we fingerprint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ChannelMode {
+    /// Mean of every channel
+    #[default]
+    Average,
+    Left,
+    Right,
+    /// (L + R) / 2
+    Mid,
+    /// (L - R) / 2, survives material whose channels cancel in a mono mix
+    Side,
+    /// A single channel by index
+    Index(usize),
+}
+
+impl ChannelMode {
+    /// Folds one interleaved frame into a mono sample.
+    /// Mono sources fall back to their only channel for every mode that needs two.
+    fn mix(self, frame: &[f32]) -> f32 {
+        let left = frame[0];
+        let right = frame.get(1).copied().unwrap_or(left);
+        match self {
+            ChannelMode::Average => frame.iter().sum::<f32>() / frame.len() as f32,
+            ChannelMode::Left => left,
+            ChannelMode::Right => right,
+            ChannelMode::Mid => (left + right) * 0.5,
+            ChannelMode::Side => (left - right) * 0.5,
+            ChannelMode::Index(i) => frame.get(i).copied().unwrap_or(0.0),
+        }
+    }
+}
+
+/// Which track of a multi-track container gets decoded.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TrackSelector {
+    /// Whatever the container marks as default
+    #[default]
+    Default,
+    /// The first track that carries audio
+    FirstAudio,
+    Id(u32),
+    /// First audio track with this language tag, e.g. "eng"
+    Language(String),
+}
 
-        match decoder.decode(&packet) {
-            Ok(decoded) => {
-                let spec = *decoded.spec();
-                sample_rate = spec.rate;
-                let duration = decoded.capacity() as u64;
-                let mut buf = symphonia::core::audio::SampleBuffer::<f32>::new(duration, spec);
-                buf.copy_interleaved_ref(decoded);
+impl TrackSelector {
+    fn select<'a>(&self, format: &'a dyn FormatReader) -> Option<&'a Track> {
+        let is_audio = |track: &&Track| {
+            track.codec_params.codec != CODEC_TYPE_NULL && track.codec_params.sample_rate.is_some()
+        };
 
-                // Convert Stereo to Mono by averaging channels
-                let channel_count = spec.channels.count();
-                for frame in buf.samples().chunks(channel_count) {
-                    let mono_sample: f32 = frame.iter().sum::<f32>() / channel_count as f32;
-                    samples.push(mono_sample);
+        match self {
+            TrackSelector::Default => format.default_track(),
+            TrackSelector::FirstAudio => format.tracks().iter().find(is_audio),
+            TrackSelector::Id(id) => format.tracks().iter().find(|track| track.id == *id),
+            TrackSelector::Language(language) => format.tracks().iter()
+                .filter(is_audio)
+                .find(|track| track.language.as_deref() == Some(language.as_str())),
+        }
+    }
+}
+
+fn duration_to_frames(duration: Duration, sample_rate: u32) -> usize {
+    (duration.as_secs_f64() * sample_rate as f64).round() as usize
+}
+
+fn time_to_frames(time: Time, sample_rate: u32) -> usize {
+    ((time.seconds as f64 + time.frac) * sample_rate as f64).round() as usize
+}
+
+/// Everything that can go wrong while turning a file into mono samples.
+///
+/// `path` is `None` when decoding from a bare `File` and is filled in by the
+/// path based loaders.
+#[derive(Debug)]
+pub enum AudioLoadError {
+    Io { path: Option<PathBuf>, source: std::io::Error },
+    UnsupportedContainer { path: Option<PathBuf>, source: Error },
+    NoAudioTrack { path: Option<PathBuf> },
+    UnsupportedCodec { path: Option<PathBuf>, source: Error },
+    NoSamples { path: Option<PathBuf>, failed_packets: usize },
+    ChannelOutOfRange { path: Option<PathBuf>, channel: usize, channels: usize },
+    InvalidPcmConfig { path: Option<PathBuf>, reason: &'static str },
+    Resample { path: Option<PathBuf>, source: DownsampleError },
+    Spectrogram { path: Option<PathBuf>, source: SpectrogramConfigError },
+    Fingerprint { path: Option<PathBuf>, source: FingerprintConfigError },
+}
+
+impl AudioLoadError {
+    /// Attaches the file path to the error if it doesn't carry one yet.
+    pub fn with_path(mut self, file_path: &Path) -> Self {
+        match &mut self {
+            AudioLoadError::Io { path, .. }
+            | AudioLoadError::UnsupportedContainer { path, .. }
+            | AudioLoadError::NoAudioTrack { path }
+            | AudioLoadError::UnsupportedCodec { path, .. }
+            | AudioLoadError::NoSamples { path, .. }
+            | AudioLoadError::ChannelOutOfRange { path, .. }
+            | AudioLoadError::InvalidPcmConfig { path, .. }
+            | AudioLoadError::Resample { path, .. }
+            | AudioLoadError::Spectrogram { path, .. }
+            | AudioLoadError::Fingerprint { path, .. } => {
+                if path.is_none() {
+                    *path = Some(file_path.to_path_buf());
                 }
             }
-            Err(Error::DecodeError(_)) => (),
-            Err(_) => break,
         }
+        self
+    }
 
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            AudioLoadError::Io { path, .. }
+            | AudioLoadError::UnsupportedContainer { path, .. }
+            | AudioLoadError::NoAudioTrack { path }
+            | AudioLoadError::UnsupportedCodec { path, .. }
+            | AudioLoadError::NoSamples { path, .. }
+            | AudioLoadError::ChannelOutOfRange { path, .. }
+            | AudioLoadError::InvalidPcmConfig { path, .. }
+            | AudioLoadError::Resample { path, .. }
+            | AudioLoadError::Spectrogram { path, .. }
+            | AudioLoadError::Fingerprint { path, .. } => path.as_deref(),
+        }
     }
-    (samples, sample_rate)
 
+    /// Number of packets that failed to decode before the error was raised.
+    pub fn failed_packets(&self) -> usize {
+        match self {
+            AudioLoadError::NoSamples { failed_packets, .. } => *failed_packets,
+            _ => 0,
+        }
+    }
 }
 
+impl fmt::Display for AudioLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "<stream>".to_string());
+
+        match self {
+            AudioLoadError::Io { source, .. } => write!(f, "{}: failed to open media: {}", path, source),
+            AudioLoadError::UnsupportedContainer { source, .. } => write!(f, "{}: unsupported format: {}", path, source),
+            AudioLoadError::NoAudioTrack { .. } => write!(f, "{}: no audio track found", path),
+            AudioLoadError::UnsupportedCodec { source, .. } => write!(f, "{}: unsupported codec: {}", path, source),
+            AudioLoadError::NoSamples { failed_packets, .. } => write!(
+                f, "{}: no samples decoded ({} packets failed to decode)", path, failed_packets
+            ),
+            AudioLoadError::ChannelOutOfRange { channel, channels, .. } => write!(
+                f, "{}: channel {} requested but the track has {} channels", path, channel, channels
+            ),
+            AudioLoadError::InvalidPcmConfig { reason, .. } => write!(f, "{}: invalid raw PCM config: {}", path, reason),
+            AudioLoadError::Resample { source, .. } => write!(f, "{}: {}", path, source),
+            AudioLoadError::Spectrogram { source, .. } => write!(f, "{}: invalid spectrogram config: {}", path, source),
+            AudioLoadError::Fingerprint { source, .. } => write!(f, "{}: invalid fingerprint config: {}", path, source),
+        }
+    }
+}
+
+impl From<DownsampleError> for AudioLoadError {
+    fn from(source: DownsampleError) -> Self {
+        AudioLoadError::Resample { path: None, source }
+    }
+}
+
+impl From<SpectrogramConfigError> for AudioLoadError {
+    fn from(source: SpectrogramConfigError) -> Self {
+        AudioLoadError::Spectrogram { path: None, source }
+    }
+}
+
+impl From<FingerprintConfigError> for AudioLoadError {
+    fn from(source: FingerprintConfigError) -> Self {
+        AudioLoadError::Fingerprint { path: None, source }
+    }
+}
+
+impl std::error::Error for AudioLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioLoadError::Io { source, .. } => Some(source),
+            AudioLoadError::UnsupportedContainer { source, .. }
+            | AudioLoadError::UnsupportedCodec { source, .. } => Some(source),
+            AudioLoadError::Resample { source, .. } => Some(source),
+            AudioLoadError::Spectrogram { source, .. } => Some(source),
+            AudioLoadError::Fingerprint { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Codecs usually found behind each file extension, lower case.
+const EXTENSION_CODECS: &[(&str, &[CodecType])] = &[
+    ("mp3", &[CODEC_TYPE_MP3]),
+    ("mp2", &[CODEC_TYPE_MP2]),
+    ("mp1", &[CODEC_TYPE_MP1]),
+    ("wav", &[CODEC_TYPE_PCM_S16LE, CODEC_TYPE_ADPCM_IMA_WAV]),
+    ("wave", &[CODEC_TYPE_PCM_S16LE, CODEC_TYPE_ADPCM_IMA_WAV]),
+    ("flac", &[CODEC_TYPE_FLAC]),
+    ("ogg", &[CODEC_TYPE_VORBIS, CODEC_TYPE_FLAC]),
+    ("oga", &[CODEC_TYPE_VORBIS, CODEC_TYPE_FLAC]),
+    ("m4a", &[CODEC_TYPE_AAC, CODEC_TYPE_ALAC]),
+    ("m4b", &[CODEC_TYPE_AAC]),
+    ("mp4", &[CODEC_TYPE_AAC]),
+    ("aac", &[CODEC_TYPE_AAC]),
+    ("mkv", &[CODEC_TYPE_VORBIS, CODEC_TYPE_FLAC, CODEC_TYPE_AAC]),
+    ("mka", &[CODEC_TYPE_VORBIS, CODEC_TYPE_FLAC, CODEC_TYPE_AAC]),
+    ("webm", &[CODEC_TYPE_VORBIS]),
+    ("caf", &[CODEC_TYPE_PCM_S16LE, CODEC_TYPE_ALAC]),
+    ("aif", &[CODEC_TYPE_PCM_S16BE]),
+    ("aiff", &[CODEC_TYPE_PCM_S16BE]),
+    ("aifc", &[CODEC_TYPE_PCM_S16BE]),
+];
+
+/// File extensions the decoder can handle, lower case.
+///
+/// Follows symphonia's codec registry, so the list only names extensions whose
+/// codecs the build can decode. Whether the container itself is readable is
+/// only known once a file is probed.
+pub fn supported_extensions() -> Vec<&'static str> {
+    let codecs = symphonia::default::get_codecs();
+    EXTENSION_CODECS.iter()
+        .filter(|(_, types)| types.iter().any(|codec| codecs.get_codec(*codec).is_some()))
+        .map(|(extension, _)| *extension)
+        .collect()
+}
+
+/// Best guess at the MIME type of a file extension, used as a probe hint.
+pub fn mime_type_for_extension(extension: &str) -> Option<&'static str> {
+    let mime = match extension.to_ascii_lowercase().as_str() {
+        "mp3" | "mp2" | "mp1" => "audio/mpeg",
+        "wav" | "wave" => "audio/wav",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "m4a" | "m4b" => "audio/mp4",
+        "mp4" => "video/mp4",
+        "aac" => "audio/aac",
+        "mkv" => "video/x-matroska",
+        "mka" => "audio/x-matroska",
+        "webm" => "audio/webm",
+        "caf" => "audio/x-caf",
+        "aif" | "aiff" | "aifc" => "audio/aiff",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// Builds a probe hint from a file's extension and the MIME type it implies.
+pub fn hint_for_path(path: &Path) -> Hint {
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(&ext.to_ascii_lowercase());
+        if let Some(mime) = mime_type_for_extension(ext) {
+            hint.mime_type(mime);
+        }
+    }
+    hint
+}
+
+/// Adapts any seekable reader to symphonia's `MediaSource`.
+struct ReaderSource<R> {
+    inner: R,
+}
+
+impl<R: Read> Read for ReaderSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for ReaderSource<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<R: Read + Seek + Send + Sync> MediaSource for ReaderSource<R> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Pull-based decoder that yields the track as mono blocks, one per packet.
+///
+/// Only a single packet worth of samples is held at a time, so memory use
+/// doesn't grow with the length of the file. A read or container error ends the
+/// stream early, check `error()` once it is drained.
+pub struct AudioStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    channels: usize,
+    channel_mode: ChannelMode,
+    failed_packets: usize,
+    error: Option<std::io::Error>,
+    // A block decoded ahead of time to discover the sample rate
+    pending: Option<Vec<f32>>,
+    // Frames still to drop before the requested start
+    skip_frames: usize,
+    // Frames left until the requested end, `None` when decoding to the end
+    remaining_frames: Option<usize>,
+    metadata: AudioMetadata,
+    // Mono frames decoded so far, used for the exact duration
+    decoded_frames: u64,
+    // Mono frames handed out so far, after trimming to the window
+    emitted_frames: u64,
+    seeked: bool,
+    finished: bool,
+}
+
+impl AudioStream {
+    pub fn open(path: &Path) -> Result<Self, AudioLoadError> {
+        Self::open_with_options(path, &LoadOptions::default())
+    }
+
+    pub fn open_with_options(path: &Path, options: &LoadOptions) -> Result<Self, AudioLoadError> {
+        let src = File::open(path)
+            .map_err(|source| AudioLoadError::Io { path: Some(path.to_path_buf()), source })?;
+        Self::from_media_source(Box::new(src), &hint_for_path(path), options)
+            .map_err(|e| e.with_path(path))
+    }
+
+    pub fn from_file(file: File) -> Result<Self, AudioLoadError> {
+        Self::from_media_source(Box::new(file), &Hint::new(), &LoadOptions::default())
+    }
+
+    /// Decodes from any seekable reader, e.g. an upload body or an archive member.
+    pub fn from_reader<R>(reader: R) -> Result<Self, AudioLoadError>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        Self::from_reader_with_hint(reader, &Hint::new())
+    }
+
+    /// Like `from_reader`, with the extension or MIME type of the source to speed up probing.
+    pub fn from_reader_with_hint<R>(reader: R, hint: &Hint) -> Result<Self, AudioLoadError>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        Self::from_reader_with_options(reader, hint, &LoadOptions::default())
+    }
+
+    pub fn from_reader_with_options<R>(reader: R, hint: &Hint, options: &LoadOptions) -> Result<Self, AudioLoadError>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        Self::from_media_source(Box::new(ReaderSource { inner: reader }), hint, options)
+    }
+
+    /// Decodes from an in-memory buffer, e.g. a `Vec<u8>` or an `include_bytes!` fixture.
+    pub fn from_bytes<B>(bytes: B) -> Result<Self, AudioLoadError>
+    where
+        B: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        Self::from_media_source(Box::new(Cursor::new(bytes)), &Hint::new(), &LoadOptions::default())
+    }
+
+    fn from_media_source(
+        source: Box<dyn MediaSource>,
+        hint: &Hint,
+        options: &LoadOptions,
+    ) -> Result<Self, AudioLoadError> {
+        let mss = MediaSourceStream::new(source, Default::default());
+
+        let probed = symphonia::default::get_probe()
+            .format(hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|source| AudioLoadError::UnsupportedContainer { path: None, source })?;
+
+        // Container tags first, then anything found while probing (e.g. an ID3 header)
+        let mut metadata = AudioMetadata::default();
+        let mut format = probed.format;
+        if let Some(revision) = format.metadata().current() {
+            metadata.read_tags(revision);
+        }
+        let mut probed_metadata = probed.metadata;
+        if let Some(revision) = probed_metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.read_tags(revision);
+        }
+
+        let track = options.track.select(format.as_ref())
+            .ok_or(AudioLoadError::NoAudioTrack { path: None })?;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|source| AudioLoadError::UnsupportedCodec { path: None, source })?;
+
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+        let channels = track.codec_params.channels.map(|c| c.count()).unwrap_or(0);
+        let n_frames = track.codec_params.n_frames;
+        metadata.bits_per_sample = track.codec_params.bits_per_sample;
+        metadata.codec = symphonia::default::get_codecs()
+            .get_codec(track.codec_params.codec)
+            .map(|descriptor| descriptor.short_name.to_string());
+
+        let mut stream = AudioStream {
+            format,
+            decoder,
+            track_id,
+            time_base,
+            sample_rate,
+            channels,
+            channel_mode: options.channel_mode,
+            failed_packets: 0,
+            error: None,
+            pending: None,
+            skip_frames: 0,
+            remaining_frames: None,
+            metadata,
+            decoded_frames: 0,
+            emitted_frames: 0,
+            seeked: false,
+            finished: false,
+        };
+
+        // Some containers only reveal the rate and layout once the first packet is decoded
+        if stream.sample_rate == 0 || stream.channels == 0 {
+            stream.pending = stream.decode_next();
+            if let Some(source) = stream.error.take() {
+                return Err(AudioLoadError::Io { path: None, source });
+            }
+            if stream.pending.is_none() {
+                return Err(AudioLoadError::NoSamples { path: None, failed_packets: stream.failed_packets });
+            }
+        }
+
+        stream.metadata.sample_rate = stream.sample_rate;
+        stream.metadata.channels = stream.channels;
+        stream.metadata.duration = match (n_frames, time_base) {
+            (Some(n_frames), _) if stream.sample_rate > 0 => {
+                Some(Duration::from_secs_f64(n_frames as f64 / stream.sample_rate as f64))
+            }
+            (Some(n_frames), Some(time_base)) => {
+                let time = time_base.calc_time(n_frames);
+                Some(Duration::from_secs_f64(time.seconds as f64 + time.frac))
+            }
+            _ => None,
+        };
+
+        if let ChannelMode::Index(channel) = stream.channel_mode {
+            if channel >= stream.channels {
+                return Err(AudioLoadError::ChannelOutOfRange { path: None, channel, channels: stream.channels });
+            }
+        }
+
+        if let Some(start) = options.start.filter(|start| !start.is_zero()) {
+            stream.seek_to(start);
+        }
+        if let Some(duration) = options.duration {
+            stream.remaining_frames = Some(duration_to_frames(duration, stream.sample_rate));
+        }
+        Ok(stream)
+    }
+
+    /// Positions the stream so the next block starts exactly at `start`.
+    fn seek_to(&mut self, start: Duration) {
+        self.seeked = true;
+        let time = Time::new(start.as_secs(), start.subsec_nanos() as f64 / 1_000_000_000.0);
+        let seek = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time { time, track_id: Some(self.track_id) },
+        );
+
+        match seek {
+            Ok(seeked) => {
+                self.decoder.reset();
+                self.pending = None;
+
+                // An accurate seek can land slightly before the target, drop the difference
+                let lead = seeked.required_ts.saturating_sub(seeked.actual_ts);
+                self.skip_frames = match self.time_base {
+                    Some(time_base) => time_to_frames(time_base.calc_time(lead), self.sample_rate),
+                    None => lead as usize,
+                };
+            }
+            // Unseekable source, decode from the beginning and throw away the lead-in
+            Err(_) => self.skip_frames = duration_to_frames(start, self.sample_rate),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Tags and technical details read while opening the stream.
+    /// If the container doesn't declare a length, `duration` is filled in once the
+    /// whole file has been decoded, or with the length of the window once it has been
+    /// drained when decoding started past the beginning.
+    pub fn metadata(&self) -> &AudioMetadata {
+        &self.metadata
+    }
+
+    /// Length of the audio handed out so far, the whole selected window once drained.
+    pub fn window_duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.emitted_frames as f64 / self.sample_rate as f64)
+    }
+
+    /// Called when the window is drained. A seeked stream never decodes the whole file,
+    /// so an undeclared duration can only be the window's.
+    fn finish_window(&mut self) {
+        if self.metadata.duration.is_none() && self.seeked && self.error.is_none() {
+            self.metadata.duration = Some(self.window_duration()).filter(|d| !d.is_zero());
+        }
+    }
+
+    /// Channels in the source track, before they are folded to mono.
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Packets skipped so far because symphonia reported them as corrupt.
+    pub fn failed_packets(&self) -> usize {
+        self.failed_packets
+    }
+
+    /// The read or container error that stopped the stream, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+
+    /// Decodes packets until one yields audio for our track.
+    fn decode_next(&mut self) -> Option<Vec<f32>> {
+        if self.finished {
+            return None;
+        }
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.error = stream_error(e);
+                    break;
+                }
+            };
+            if packet.track_id() != self.track_id { continue; }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let spec = *decoded.spec();
+                    self.sample_rate = spec.rate;
+                    let duration = decoded.capacity() as u64;
+                    let mut buf = symphonia::core::audio::SampleBuffer::<f32>::new(duration, spec);
+                    buf.copy_interleaved_ref(decoded);
+
+                    // Fold the channels into mono according to the channel mode
+                    let channel_count = spec.channels.count();
+                    self.channels = channel_count;
+                    let mode = self.channel_mode;
+                    let block: Vec<f32> = buf.samples()
+                        .chunks(channel_count)
+                        .map(|frame| mode.mix(frame))
+                        .collect();
+                    self.decoded_frames += block.len() as u64;
+                    return Some(block);
+                }
+                // Corrupt packets are skipped, but we keep count so callers can tell
+                Err(Error::DecodeError(_)) => self.failed_packets += 1,
+                Err(e) => {
+                    self.error = stream_error(e);
+                    break;
+                }
+            }
+        }
+        self.finished = true;
+
+        // Without a declared length, counting what we decoded is the exact duration
+        if self.metadata.duration.is_none() && !self.seeked && self.error.is_none() && self.sample_rate > 0 {
+            self.metadata.duration = Some(Duration::from_secs_f64(self.decoded_frames as f64 / self.sample_rate as f64));
+        }
+        None
+    }
+}
+
+/// Splits the errors that end a symphonia stream into a normal end and real failures.
+fn stream_error(e: Error) -> Option<std::io::Error> {
+    match e {
+        // Running out of packets is how symphonia reports the end of the file
+        Error::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+        // The next track in a chained stream starts, ours has ended
+        Error::ResetRequired => None,
+        Error::IoError(e) => Some(e),
+        e => Some(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+    }
+}
+
+impl Iterator for AudioStream {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        loop {
+            let block = match self.remaining_frames {
+                Some(0) => None,
+                _ => self.pending.take().or_else(|| self.decode_next()),
+            };
+            let Some(mut block) = block else {
+                self.finish_window();
+                return None;
+            };
+
+            if self.skip_frames > 0 {
+                let skip = self.skip_frames.min(block.len());
+                block.drain(..skip);
+                self.skip_frames -= skip;
+                if block.is_empty() {
+                    continue;
+                }
+            }
+            if let Some(remaining) = self.remaining_frames.as_mut() {
+                block.truncate(*remaining);
+                *remaining -= block.len();
+            }
+            self.emitted_frames += block.len() as u64;
+            return Some(block);
+        }
+    }
+}
+
+pub fn load_audio_mono(path: &str) -> Result<DecodedAudio, AudioLoadError> {
+    load_audio_from_path(Path::new(path))
+}
+pub fn load_audio_from_path(path: &Path) -> Result<DecodedAudio, AudioLoadError> {
+    load_audio_with_options(path, &LoadOptions::default())
+}
+/// Loads only the window of the file described by `options`.
+pub fn load_audio_with_options(path: &Path, options: &LoadOptions) -> Result<DecodedAudio, AudioLoadError> {
+    collect_stream(AudioStream::open_with_options(path, options)?).map_err(|e| e.with_path(path))
+}
+pub fn extract_audio(file: File) -> Result<DecodedAudio, AudioLoadError> {
+    collect_stream(AudioStream::from_file(file)?)
+}
+pub fn extract_audio_from_reader<R>(reader: R) -> Result<DecodedAudio, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    collect_stream(AudioStream::from_reader(reader)?)
+}
+pub fn extract_audio_from_bytes<B>(bytes: B) -> Result<DecodedAudio, AudioLoadError>
+where
+    B: AsRef<[u8]> + Send + Sync + 'static,
+{
+    collect_stream(AudioStream::from_bytes(bytes)?)
+}
+
+/// Drains a stream into a single buffer.
+fn collect_stream(mut stream: AudioStream) -> Result<DecodedAudio, AudioLoadError> {
+
+    let mut samples: Vec<f32> = Vec::new();
+    for block in &mut stream {
+        samples.extend_from_slice(&block);
+    }
+    if let Some(source) = stream.take_error() {
+        return Err(AudioLoadError::Io { path: None, source });
+    }
+
+    if samples.is_empty() {
+        return Err(AudioLoadError::NoSamples { path: None, failed_packets: stream.failed_packets });
+    }
+    Ok(DecodedAudio {
+        samples,
+        sample_rate: stream.sample_rate,
+        failed_packets: stream.failed_packets,
+        metadata: stream.metadata,
+    })
+}
+
+/// Sample encodings understood by the raw PCM reader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcmFormat {
+    U8,
+    S16,
+    S24,
+    S32,
+    F32,
+}
+
+impl PcmFormat {
+    fn name(self) -> &'static str {
+        match self {
+            PcmFormat::U8 => "pcm_u8",
+            PcmFormat::S16 => "pcm_s16",
+            PcmFormat::S24 => "pcm_s24",
+            PcmFormat::S32 => "pcm_s32",
+            PcmFormat::F32 => "pcm_f32",
+        }
+    }
+
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            PcmFormat::U8 => 1,
+            PcmFormat::S16 => 2,
+            PcmFormat::S24 => 3,
+            PcmFormat::S32 | PcmFormat::F32 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Layout of a headerless PCM stream, which can't be probed.
+#[derive(Clone, Debug)]
+pub struct RawPcmConfig {
+    pub format: PcmFormat,
+    pub endianness: Endianness,
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub channel_mode: ChannelMode,
+}
+
+impl RawPcmConfig {
+    /// Interleaved s16le, the usual output of capture tools.
+    pub fn s16le(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            format: PcmFormat::S16,
+            endianness: Endianness::Little,
+            channels,
+            sample_rate,
+            channel_mode: ChannelMode::Average,
+        }
+    }
+
+    /// Interleaved f32le.
+    pub fn f32le(channels: usize, sample_rate: u32) -> Self {
+        Self { format: PcmFormat::F32, ..Self::s16le(channels, sample_rate) }
+    }
+
+    fn validate(&self) -> Result<(), AudioLoadError> {
+        let reason = if self.channels == 0 {
+            "channel count must be at least 1"
+        } else if self.sample_rate == 0 {
+            "sample rate must be non-zero"
+        } else {
+            return match self.channel_mode {
+                ChannelMode::Index(channel) if channel >= self.channels => Err(
+                    AudioLoadError::ChannelOutOfRange { path: None, channel, channels: self.channels }
+                ),
+                _ => Ok(()),
+            };
+        };
+        Err(AudioLoadError::InvalidPcmConfig { path: None, reason })
+    }
+
+    /// Converts one sample from its raw bytes to f32 in -1.0..1.0.
+    fn decode_sample(&self, bytes: &[u8]) -> f32 {
+        let mut raw = [0u8; 4];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        // Normalise to little endian so the conversions below only deal with one order
+        if self.endianness == Endianness::Big {
+            raw[..bytes.len()].reverse();
+        }
+
+        match self.format {
+            PcmFormat::U8 => (raw[0] as f32 - 128.0) / 128.0,
+            PcmFormat::S16 => i16::from_le_bytes([raw[0], raw[1]]) as f32 / 32768.0,
+            // Shift into the top of an i32 so the sign bit lands in the right place
+            PcmFormat::S24 => (i32::from_le_bytes([0, raw[0], raw[1], raw[2]]) >> 8) as f32 / 8_388_608.0,
+            PcmFormat::S32 => i32::from_le_bytes(raw) as f32 / 2_147_483_648.0,
+            PcmFormat::F32 => f32::from_le_bytes(raw),
+        }
+    }
+}
+
+// Frames read from the pipe per block
+const RAW_BLOCK_FRAMES: usize = 4096;
+
+/// Reads headerless PCM from any `Read` (stdin, a FIFO, a socket) and yields
+/// mono blocks, like `AudioStream` does for containers.
+///
+/// A read error ends the stream early, check `error()` once it is drained.
+pub struct RawPcmStream<R: Read> {
+    reader: R,
+    config: RawPcmConfig,
+    buffer: Vec<u8>,
+    // Bytes of an incomplete frame carried over to the next read
+    filled: usize,
+    error: Option<std::io::Error>,
+    finished: bool,
+}
+
+impl<R: Read> RawPcmStream<R> {
+    pub fn new(reader: R, config: RawPcmConfig) -> Result<Self, AudioLoadError> {
+        config.validate()?;
+        let frame_bytes = config.format.bytes_per_sample() * config.channels;
+        Ok(Self {
+            reader,
+            config,
+            buffer: vec![0u8; frame_bytes * RAW_BLOCK_FRAMES],
+            filled: 0,
+            error: None,
+            finished: false,
+        })
+    }
+
+    /// The read error that stopped the stream, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.config.channels
+    }
+
+    /// Technical details implied by the config, raw PCM carries no tags.
+    pub fn metadata(&self) -> AudioMetadata {
+        AudioMetadata {
+            codec: Some(self.config.format.name().to_string()),
+            sample_rate: self.config.sample_rate,
+            channels: self.config.channels,
+            bits_per_sample: Some(8 * self.config.format.bytes_per_sample() as u32),
+            ..AudioMetadata::default()
+        }
+    }
+}
+
+impl<R: Read> Iterator for RawPcmStream<R> {
+    type Item = Vec<f32>;
+
+    fn next(&mut self) -> Option<Vec<f32>> {
+        if self.finished {
+            return None;
+        }
+
+        // 1. Fill the buffer, a pipe may hand us the data in arbitrary pieces
+        while self.filled < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[self.filled..]) {
+                Ok(0) => {
+                    self.finished = true;
+                    break;
+                }
+                Ok(n) => self.filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.error = Some(e);
+                    self.finished = true;
+                    break;
+                }
+            }
+        }
+
+        // 2. Convert every complete frame, a trailing partial frame at EOF is dropped
+        let sample_bytes = self.config.format.bytes_per_sample();
+        let frame_bytes = sample_bytes * self.config.channels;
+        let complete = self.filled / frame_bytes * frame_bytes;
+        if complete == 0 {
+            return None;
+        }
+
+        let mut frame = vec![0f32; self.config.channels];
+        let block: Vec<f32> = self.buffer[..complete]
+            .chunks_exact(frame_bytes)
+            .map(|raw_frame| {
+                for (sample, bytes) in frame.iter_mut().zip(raw_frame.chunks_exact(sample_bytes)) {
+                    *sample = self.config.decode_sample(bytes);
+                }
+                self.config.channel_mode.mix(&frame)
+            })
+            .collect();
+
+        // 3. Keep the leftover bytes of a split frame for the next call
+        self.buffer.copy_within(complete..self.filled, 0);
+        self.filled -= complete;
+        Some(block)
+    }
+}
+
+/// Reads a whole raw PCM stream into memory, the PCM counterpart of `extract_audio`.
+pub fn extract_raw_pcm<R: Read>(reader: R, config: RawPcmConfig) -> Result<DecodedAudio, AudioLoadError> {
+    let mut stream = RawPcmStream::new(reader, config)?;
+    let sample_rate = stream.sample_rate();
+    let mut metadata = stream.metadata();
+
+    let samples: Vec<f32> = stream.by_ref().flatten().collect();
+    if let Some(source) = stream.take_error() {
+        return Err(AudioLoadError::Io { path: None, source });
+    }
+    if samples.is_empty() {
+        return Err(AudioLoadError::NoSamples { path: None, failed_packets: 0 });
+    }
+    metadata.duration = Some(Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64));
+    Ok(DecodedAudio { samples, sample_rate, failed_packets: 0, metadata })
+}
@@ -1,9 +1,16 @@
+use std::io::{Read, Seek};
 use std::path::Path;
-use crate::load_audio_mono::load_audio_from_path;
-use crate::downsampler::downsample;
-use crate::create_spectogram::create_spectrogram;
-use crate::find_peaks::save_spectrogram_peaks;
-use crate::generate_fingerprints::generate_fingerprints;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use crate::load_audio_mono::{AudioLoadError, AudioMetadata, AudioStream, ChannelMode, LoadOptions, RawPcmConfig, RawPcmStream};
+use crate::downsampler::{DownsampleStream, ResampleQuality};
+use crate::create_spectogram::{ColumnStream, SpectrogramConfig, SpectrogramConfigError, SpectrogramStream, SpectrogramUnits};
+use crate::constant_q::{CqtConfig, CqtStream};
+use crate::find_peaks::{
+    band_edges, pick_peaks_from_stream, BandThresholdConfig, DensityConfig, DensityPicker, LocalMaxConfig, LocalMaxFinder,
+    ParabolicRefiner, PeakFinder, PeakPicker,
+};
+use crate::generate_fingerprints::{generate_fingerprints, generate_fingerprints_with_resolution, HashResolution};
 use crate::generate_fingerprints::generate_fingerprints_quad;
 use crate::generate_fingerprints::generate_fuzzy_query_hashes;
 
@@ -11,24 +18,426 @@ use crate::generate_fingerprints::generate_fuzzy_query_hashes;
 
 
 use crate::types::types::{Fingerprint, SpectrogramPoint};
+use symphonia::core::probe::Hint;
+use serde::{Serialize, Deserialize};
 
-pub fn extract_peaks(song: &Path, modifier: f32) -> Vec<SpectrogramPoint> {
-    let (samples, sample_rate) = load_audio_from_path(song);
-    let target_rate = 11025;
-    let dsample = downsample(&samples, sample_rate, target_rate);
+/// Transform turning the resampled audio into spectrogram columns.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum FrontEnd {
+    /// Fixed-resolution FFT configured by `PipelineConfig::spectrogram`
+    #[default]
+    Stft,
+    /// Semitone spaced bins, more stable under mild pitch shifting
+    ConstantQ(CqtConfig),
+}
+
+/// Peak picking strategy applied to the spectrogram columns.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PeakStrategy {
+    /// Loudest bin of each fixed band, kept when above the rolling threshold set by `modifier`
+    #[default]
+    BandMax,
+    /// Band maxima judged against their own band's recent history
+    PerBand(BandThresholdConfig),
+    /// Local maxima over a time x frequency neighbourhood
+    LocalMax(LocalMaxConfig),
+    /// The loudest local maxima of each time block, for a fixed number of peaks per second
+    Density(DensityConfig),
+}
+
+/// Settings shared by every stage of the fingerprinting chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    /// Std-dev multiplier for the peak threshold
+    pub modifier: f32,
+    /// Rate the audio is resampled to before the FFT
+    pub target_rate: u32,
+    pub resample_quality: ResampleQuality,
+    /// STFT layout, including how the edges of short queries are padded
+    pub spectrogram: SpectrogramConfig,
+    pub load: LoadOptions,
+    /// `spectrogram` is only used with `FrontEnd::Stft`
+    pub front_end: FrontEnd,
+    /// `modifier` is only used with `PeakStrategy::BandMax`
+    pub peaks: PeakStrategy,
+    /// Refine peaks to fractional bins and frames
+    pub interpolate: bool,
+    /// Grid the refined positions are hashed on by the deprecated `extract_features_*` helpers,
+    /// `Bins` ignores the refinement. A `Fingerprinter` carries its own resolution
+    pub hash_resolution: HashResolution,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            modifier: 1.0,
+            target_rate: 11025,
+            resample_quality: ResampleQuality::default(),
+            spectrogram: SpectrogramConfig::default(),
+            load: LoadOptions::default(),
+            front_end: FrontEnd::default(),
+            peaks: PeakStrategy::default(),
+            interpolate: false,
+            hash_resolution: HashResolution::default(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    pub fn with_modifier(modifier: f32) -> Self {
+        Self { modifier, ..Self::default() }
+    }
+
+    fn peak_picker(&self) -> Box<dyn PeakPicker> {
+        let picker: Box<dyn PeakPicker> = match &self.peaks {
+            PeakStrategy::BandMax => Box::new(PeakFinder::new(self.modifier)),
+            PeakStrategy::PerBand(thresholds) => Box::new(PeakFinder::with_config(thresholds.clone())),
+            PeakStrategy::LocalMax(local) => Box::new(LocalMaxFinder::new(local.clone())),
+            PeakStrategy::Density(density) => {
+                let frames_per_second = 1.0 / self.units().frame_to_seconds(1.0);
+                let candidates = LocalMaxFinder::new(density.candidates.clone());
+                Box::new(DensityPicker::new(candidates, density, frames_per_second))
+            }
+        };
+        if self.interpolate { Box::new(ParabolicRefiner::new(picker)) } else { picker }
+    }
+
+    /// Hz and seconds of the bins and frames this configuration produces.
+    pub fn units(&self) -> SpectrogramUnits {
+        match &self.front_end {
+            FrontEnd::Stft => SpectrogramUnits::new(self.target_rate, &self.spectrogram),
+            FrontEnd::ConstantQ(cqt) => cqt.units(self.target_rate),
+        }
+    }
+
+    /// Checks the settings of the front end in use.
+    pub fn validate(&self) -> Result<(), SpectrogramConfigError> {
+        match &self.front_end {
+            FrontEnd::Stft => {
+                self.spectrogram.validate()?;
+                self.spectrogram.frequency.validate(self.target_rate as f32 / 2.0)
+            }
+            FrontEnd::ConstantQ(cqt) => cqt.validate(self.target_rate),
+        }
+    }
+
+    /// Bins per spectrogram column.
+    pub fn bins(&self) -> Result<usize, SpectrogramConfigError> {
+        match &self.front_end {
+            FrontEnd::Stft => self.spectrogram.bins(self.target_rate),
+            FrontEnd::ConstantQ(cqt) => Ok(cqt.bins(self.target_rate)),
+        }
+    }
+
+    /// Snaps the requested start down to a frame boundary.
+    /// Returns the options to decode with and the index of the first frame
+    /// on the original file's timeline.
+    fn aligned_load(&self) -> Result<(LoadOptions, usize), SpectrogramConfigError> {
+        // The hop has to be valid before it can be used to align anything
+        self.validate()?;
+        let mut load = self.load.clone();
+        let start = match load.start {
+            Some(start) => start,
+            None => return Ok((load, 0)),
+        };
+
+        let frame_secs = self.units().hop_size as f64 / self.target_rate as f64;
+        let start_frame = (start.as_secs_f64() / frame_secs).floor() as usize;
+        let aligned = Duration::from_secs_f64(start_frame as f64 * frame_secs);
+
+        // Keep the end of the window where it was asked for
+        if let Some(duration) = load.duration {
+            load.duration = Some(duration + (start - aligned));
+        }
+        load.start = Some(aligned);
+        Ok((load, start_frame))
+    }
+}
+
+pub fn extract_peaks(song: &Path, modifier: f32) -> Result<Vec<SpectrogramPoint>, AudioLoadError> {
+    extract_peaks_with_config(song, &PipelineConfig::with_modifier(modifier))
+}
+
+/// Peaks for the part of `song` selected by `config.load`.
+/// `time_idx` stays relative to the start of the file, not the start of the window.
+pub fn extract_peaks_with_config(song: &Path, config: &PipelineConfig) -> Result<Vec<SpectrogramPoint>, AudioLoadError> {
+    Ok(extract_peaks_with_metadata(song, config)?.0)
+}
+
+/// Like `extract_peaks_with_config`, also returning the tags and technical
+/// details collected while decoding.
+pub fn extract_peaks_with_metadata(song: &Path, config: &PipelineConfig) -> Result<(Vec<SpectrogramPoint>, AudioMetadata), AudioLoadError> {
+    let (peaks, audio) = extract_peaks_with_stream(song, config)?;
+    Ok((peaks, audio.metadata().clone()))
+}
 
-    let spectrum = create_spectrogram(&dsample);
-    save_spectrogram_peaks(&spectrum, modifier)
-    
+/// Peaks of `song` along with the drained stream, for what it measured while decoding.
+fn extract_peaks_with_stream(song: &Path, config: &PipelineConfig) -> Result<(Vec<SpectrogramPoint>, AudioStream), AudioLoadError> {
+    let (load, start_frame) = config.aligned_load()?;
+    let mut audio = AudioStream::open_with_options(song, &load)?;
+    let peaks = peaks_from_stream(&mut audio, config, start_frame).map_err(|e| e.with_path(song))?;
+    Ok((peaks, audio))
 }
-pub fn extract_features(song: &Path, modifier: f32) -> Vec<Fingerprint>{
-    generate_fingerprints(extract_peaks(song, modifier).as_slice())
+
+/// One peak set per channel of the selected track, ignoring `config.load.channel_mode`.
+///
+/// Each channel is decoded in its own pass so memory stays bounded the same way
+/// as for the mono path. That costs one full decode per channel, so a stereo file
+/// takes about twice as long as `extract_peaks_with_metadata`.
+pub fn extract_peaks_per_channel(song: &Path, config: &PipelineConfig) -> Result<(Vec<Vec<SpectrogramPoint>>, AudioMetadata), AudioLoadError> {
+    // Only opened to count the channels, a channel index meant for another file must not fail it
+    let probe_load = LoadOptions { channel_mode: ChannelMode::default(), ..config.load.clone() };
+    let probe = AudioStream::open_with_options(song, &probe_load)?;
+    let channels = probe.channel_count();
+    let mut metadata = probe.metadata().clone();
+
+    let mut sets = Vec::with_capacity(channels);
+    for channel in 0..channels {
+        let mut channel_config = config.clone();
+        channel_config.load.channel_mode = ChannelMode::Index(channel);
+        let (peaks, channel_metadata) = extract_peaks_with_metadata(song, &channel_config)?;
+        // A fully decoded pass knows the exact duration even if the header didn't
+        metadata = channel_metadata;
+        sets.push(peaks);
+    }
+    Ok((sets, metadata))
+}
+
+/// Same as `extract_peaks` but reads the encoded audio from memory or any other reader.
+/// Wrap a byte buffer in `std::io::Cursor` to use it here.
+pub fn extract_peaks_from_reader<R>(reader: R, modifier: f32) -> Result<Vec<SpectrogramPoint>, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    extract_peaks_from_reader_with_config(reader, &PipelineConfig::with_modifier(modifier))
+}
+
+pub fn extract_peaks_from_reader_with_config<R>(reader: R, config: &PipelineConfig) -> Result<Vec<SpectrogramPoint>, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    let (load, start_frame) = config.aligned_load()?;
+    let mut audio = AudioStream::from_reader_with_options(reader, &Hint::new(), &load)?;
+    peaks_from_stream(&mut audio, config, start_frame)
+}
+
+/// Peaks for headerless PCM read from a pipe. `config.load` is ignored, the
+/// channel mode comes from `pcm` and the whole stream is used.
+pub fn extract_peaks_from_raw_pcm<R: Read>(reader: R, pcm: RawPcmConfig, config: &PipelineConfig) -> Result<Vec<SpectrogramPoint>, AudioLoadError> {
+    let mut audio = RawPcmStream::new(reader, pcm)?;
+    let sample_rate = audio.sample_rate();
+    let peaks = peaks_from_blocks(&mut audio, sample_rate, config, 0)?;
+    match audio.take_error() {
+        Some(source) => Err(AudioLoadError::Io { path: None, source }),
+        None => Ok(peaks),
+    }
+}
+
+fn peaks_from_stream(audio: &mut AudioStream, config: &PipelineConfig, start_frame: usize) -> Result<Vec<SpectrogramPoint>, AudioLoadError> {
+    let sample_rate = audio.sample_rate();
+    let peaks = peaks_from_blocks(&mut *audio, sample_rate, config, start_frame)?;
+    // A truncated or unreadable file must not pass for a short one
+    match audio.take_error() {
+        Some(source) => Err(AudioLoadError::Io { path: None, source }),
+        None => Ok(peaks),
+    }
+}
+
+fn peaks_from_blocks<I: Iterator<Item = Vec<f32>>>(
+    audio: I,
+    sample_rate: u32,
+    config: &PipelineConfig,
+    start_frame: usize,
+) -> Result<Vec<SpectrogramPoint>, AudioLoadError> {
+    // Every stage pulls blocks from the previous one, so the whole track is never in memory
+    let mut dsample = DownsampleStream::new(audio, sample_rate, config.target_rate, config.resample_quality)?;
+    let mut spectrum: Box<dyn ColumnStream + '_> = match &config.front_end {
+        FrontEnd::Stft => Box::new(SpectrogramStream::new(&mut dsample, config.target_rate, &config.spectrogram)?),
+        FrontEnd::ConstantQ(cqt) => Box::new(CqtStream::new(&mut dsample, config.target_rate, cqt)?),
+    };
+    let mut peaks = pick_peaks_from_stream(spectrum.as_mut(), config.peak_picker().as_mut());
+    drop(spectrum);
+    if let Some(e) = dsample.take_error() {
+        return Err(e.into());
+    }
+
+    // Shift back onto the original file's timeline
+    for peak in peaks.iter_mut() {
+        peak.time_idx += start_frame;
+        peak.time_pos += start_frame as f32;
+    }
+    Ok(peaks)
+}
+
+/// Peaks found in one band of the band-max layout.
+#[derive(Clone, Debug)]
+pub struct BandReport {
+    /// First and one-past-last bin
+    pub bins: (usize, usize),
+    pub low_hz: f32,
+    pub high_hz: f32,
+    pub peaks: usize,
+}
+
+/// Counts the peaks of `song` per band, to tune the thresholds of each band.
+/// Works for every peak strategy, local maxima are sorted into the same bands.
+pub fn band_peak_counts(song: &Path, config: &PipelineConfig) -> Result<Vec<BandReport>, AudioLoadError> {
+    let peaks = extract_peaks_with_config(song, config)?;
+    let units = config.units();
+
+    let mut reports: Vec<BandReport> = band_edges(config.bins()?)
+        .into_iter()
+        .map(|(start, end)| BandReport {
+            bins: (start, end),
+            low_hz: units.bin_to_hz(start as f32),
+            high_hz: units.bin_to_hz(end as f32),
+            peaks: 0,
+        })
+        .collect();
+    for peak in peaks.iter() {
+        if let Some(report) = reports.iter_mut().find(|r| (r.bins.0..r.bins.1).contains(&peak.freq_bin)) {
+            report.peaks += 1;
+        }
+    }
+    Ok(reports)
+}
+
+/// Peaks per second actually achieved on one file.
+#[derive(Clone, Debug)]
+pub struct PeakDensity {
+    pub peaks: usize,
+    /// Length of the analysed audio
+    pub seconds: f32,
+    pub peaks_per_second: f32,
+}
+
+/// Runs the peak extraction and reports the density it reached, to check a
+/// `PeakStrategy::Density` target or compare the other strategies across a catalogue.
+pub fn measure_peak_density(song: &Path, config: &PipelineConfig) -> Result<PeakDensity, AudioLoadError> {
+    let (peaks, audio) = extract_peaks_with_stream(song, config)?;
+
+    // The audio actually analysed, the window selected by `config.load` plus the
+    // few samples `aligned_load` adds in front to line up the frames
+    let seconds = audio.window_duration().as_secs_f32();
+
+    Ok(PeakDensity {
+        peaks: peaks.len(),
+        seconds,
+        peaks_per_second: if seconds > 0.0 { peaks.len() as f32 / seconds } else { 0.0 },
+    })
+}
+
+/// How one resampling profile performed against `ResampleQuality::Best`.
+#[derive(Clone, Debug)]
+pub struct ResampleReport {
+    pub quality: ResampleQuality,
+    /// Wall time of the whole decode -> peaks chain
+    pub elapsed: Duration,
+    pub peak_count: usize,
+    /// Peaks found at the same (time, bin) as with the best profile
+    pub shared_with_best: usize,
+    /// Shared peaks over the union of both peak sets, 1.0 means identical
+    pub jaccard: f32,
+}
+
+/// Runs the peak extraction once per quality profile on the same file and
+/// reports how far each one drifts from the best profile.
+/// Use it to pick the fastest profile that doesn't hurt recognition.
+pub fn compare_resample_quality(song: &Path, config: &PipelineConfig) -> Result<Vec<ResampleReport>, AudioLoadError> {
+    let mut runs = Vec::new();
+    for quality in ResampleQuality::ALL {
+        let mut run_config = config.clone();
+        run_config.resample_quality = quality;
+
+        let start = Instant::now();
+        let peaks = extract_peaks_with_config(song, &run_config)?;
+        runs.push((quality, start.elapsed(), peaks));
+    }
+
+    let key = |p: &SpectrogramPoint| (p.time_idx, p.freq_bin);
+    let reference: HashSet<(usize, usize)> = runs.iter()
+        .find(|(quality, _, _)| *quality == ResampleQuality::Best)
+        .map(|(_, _, peaks)| peaks.iter().map(key).collect())
+        .unwrap_or_default();
+
+    Ok(runs.into_iter()
+        .map(|(quality, elapsed, peaks)| {
+            let set: HashSet<(usize, usize)> = peaks.iter().map(key).collect();
+            let shared = set.intersection(&reference).count();
+            let union = set.union(&reference).count();
+            ResampleReport {
+                quality,
+                elapsed,
+                peak_count: peaks.len(),
+                shared_with_best: shared,
+                jaccard: if union == 0 { 1.0 } else { shared as f32 / union as f32 },
+            }
+        })
+        .collect())
+}
+
+/// Pair hashes of a song, picked and hashed with the default settings.
+///
+/// The `extract_features*` helpers hash with fixed settings that only match a
+/// database built with the same ones. Pick peaks with the matching `extract_peaks*`
+/// function and hash them with the database's fingerprinter instead, so indexing
+/// and queries share its pipeline.
+#[deprecated(note = "use `extract_peaks` with `Fingerprinter::index`")]
+pub fn extract_features(song: &Path, modifier: f32) -> Result<Vec<Fingerprint>, AudioLoadError>{
+    Ok(generate_fingerprints(extract_peaks(song, modifier)?.as_slice()))
+}
+
+#[deprecated(note = "use `extract_peaks` with `QuadFingerprinter`")]
+pub fn extract_features_quad(song: &Path, modifier: f32) -> Result<Vec<Fingerprint>, AudioLoadError>{
+    Ok(generate_fingerprints_quad(extract_peaks(song, modifier)?.as_slice()))
+}
+
+#[deprecated(note = "use `extract_peaks` with `AudioDatabase::find_best_match_peaks`")]
+pub fn extract_features_client_fuzzy(song: &Path, modifier:f32) -> Result<Vec<Fingerprint>, AudioLoadError>{
+    Ok(generate_fuzzy_query_hashes(extract_peaks(song, modifier)?.as_slice()))
+}
+
+#[deprecated(note = "use `extract_peaks_with_metadata` with `Fingerprinter::index`")]
+pub fn extract_features_with_metadata(song: &Path, config: &PipelineConfig) -> Result<(Vec<Fingerprint>, AudioMetadata), AudioLoadError> {
+    let (peaks, metadata) = extract_peaks_with_metadata(song, config)?;
+    Ok((generate_fingerprints_with_resolution(peaks.as_slice(), config.hash_resolution)?, metadata))
+}
+
+#[deprecated(note = "use `extract_peaks_per_channel` with `Fingerprinter::index`")]
+pub fn extract_features_per_channel(song: &Path, config: &PipelineConfig) -> Result<(Vec<Vec<Fingerprint>>, AudioMetadata), AudioLoadError> {
+    let (sets, metadata) = extract_peaks_per_channel(song, config)?;
+    let fingerprints = sets.iter()
+        .map(|peaks| generate_fingerprints_with_resolution(peaks, config.hash_resolution))
+        .collect::<Result<_, _>>()?;
+    Ok((fingerprints, metadata))
+}
+
+#[deprecated(note = "use `extract_peaks_from_raw_pcm` with `Fingerprinter::index`")]
+pub fn extract_features_from_raw_pcm<R: Read>(reader: R, pcm: RawPcmConfig, config: &PipelineConfig) -> Result<Vec<Fingerprint>, AudioLoadError> {
+    let peaks = extract_peaks_from_raw_pcm(reader, pcm, config)?;
+    Ok(generate_fingerprints_with_resolution(peaks.as_slice(), config.hash_resolution)?)
+}
+
+#[deprecated(note = "use `extract_peaks_from_reader` with `Fingerprinter::index`")]
+pub fn extract_features_from_reader<R>(reader: R, modifier: f32) -> Result<Vec<Fingerprint>, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    Ok(generate_fingerprints(extract_peaks_from_reader(reader, modifier)?.as_slice()))
 }
 
-pub fn extract_features_quad(song: &Path, modifier: f32) -> Vec<Fingerprint>{
-    generate_fingerprints_quad(extract_peaks(song, modifier).as_slice())
+#[deprecated(note = "use `extract_peaks_from_reader` with `QuadFingerprinter`")]
+pub fn extract_features_quad_from_reader<R>(reader: R, modifier: f32) -> Result<Vec<Fingerprint>, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    Ok(generate_fingerprints_quad(extract_peaks_from_reader(reader, modifier)?.as_slice()))
 }
 
-pub fn extract_features_client_fuzzy(song: &Path, modifier:f32) -> Vec<Fingerprint>{
-    generate_fuzzy_query_hashes(extract_peaks(song, modifier).as_slice())
+#[deprecated(note = "use `extract_peaks_from_reader` with `AudioDatabase::find_best_match_peaks`")]
+pub fn extract_features_client_fuzzy_from_reader<R>(reader: R, modifier: f32) -> Result<Vec<Fingerprint>, AudioLoadError>
+where
+    R: Read + Seek + Send + Sync + 'static,
+{
+    Ok(generate_fuzzy_query_hashes(extract_peaks_from_reader(reader, modifier)?.as_slice()))
 }
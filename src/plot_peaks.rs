@@ -1,4 +1,5 @@
 use plotters::prelude::*;
+use crate::create_spectogram::SpectrogramUnits;
 use crate::types::types::SpectrogramPoint;
 
 /// Plots a spectrogram heatmap to a PNG file.
@@ -13,6 +14,18 @@ pub fn plot_spectrogram(
     width: u32,
     height: u32
 ) -> Result<(), Box<dyn std::error::Error>> {
+    plot_spectrogram_units(data, None, filename, width, height)
+}
+
+/// Same as `plot_spectrogram`, with the axes in seconds and Hz when `units` is given
+/// and in frames and bins otherwise.
+pub fn plot_spectrogram_units(
+    data: &[SpectrogramPoint],
+    units: Option<&SpectrogramUnits>,
+    filename: &str,
+    width: u32,
+    height: u32
+) -> Result<(), Box<dyn std::error::Error>> {
 
     // 1. Setup the backend
     let root = BitMapBackend::new(filename, (width, height)).into_drawing_area();
@@ -23,34 +36,39 @@ pub fn plot_spectrogram(
         return Err("No data provided to plot".into());
     }
 
-    let max_time = data.iter().map(|p| p.time_idx).max().unwrap_or(0);
-    let max_freq = 255usize;
+    let position = |p: &SpectrogramPoint| match units {
+        Some(units) => (p.time_seconds(units), p.frequency_hz(units)),
+        None => (p.time_idx as f32, p.freq_bin as f32),
+    };
+    let max_time = data.iter().map(|p| position(p).0).fold(0f32, f32::max);
+    let max_freq = data.iter().map(|p| position(p).1).fold(0f32, f32::max);
 
     // Find min/max magnitude for color normalization
     let min_mag = data.iter().map(|p| p.magnitude).fold(f32::INFINITY, |a, b| a.min(b));
     let max_mag = data.iter().map(|p| p.magnitude).fold(f32::NEG_INFINITY, |a, b| a.max(b));
 
     // 3. Build the chart
+    let (x_desc, y_desc, y_label_size) = match units {
+        Some(_) => ("Time (s)", "Frequency (Hz)", 60),
+        None => ("Time Index", "Frequency Bin", 40),
+    };
     let mut chart = ChartBuilder::on(&root)
         .caption("Spectrogram Analysis", ("sans-serif", 30))
         .margin(10)
         .x_label_area_size(40)
-        .y_label_area_size(40)
-        .build_cartesian_2d(0..max_time + 1, 0..max_freq + 1)?;
+        .y_label_area_size(y_label_size)
+        .build_cartesian_2d(0f32..max_time + 1.0, 0f32..max_freq + 1.0)?;
 
     chart
         .configure_mesh()
-        .x_desc("Time Index")
-        .y_desc("Frequency Bin")
+        .x_desc(x_desc)
+        .y_desc(y_desc)
         .draw()?;
 
     // 4. Draw the Heatmap
     // We represent each point as a rectangle of size 1x1.
     // The color is interpolated between Blue (Low) and Red (High).
     chart.draw_series(data.iter().map(|point| {
-        let x = point.time_idx;
-        let y = point.freq_bin;
-
         // Normalize magnitude between 0.0 and 1.0
         let norm_mag = if max_mag == min_mag {
             0.5
@@ -65,7 +83,7 @@ pub fn plot_spectrogram(
             0.5  // Lightness
         );
 
-        Circle::new((x, y), 2, color.filled())
+        Circle::new(position(point), 2, color.filled())
     }))?;
 
     // To prevent saving partial files on error
@@ -74,4 +92,3 @@ pub fn plot_spectrogram(
 
     Ok(())
 }
-
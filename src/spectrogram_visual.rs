@@ -1,8 +1,9 @@
 use image::{ImageBuffer, Rgb};
 use std::cmp;
+use crate::create_spectogram::Spectrogram;
 
 pub fn save_spectrogram_image(
-    spectrogram: &[Vec<f32>],
+    spectrogram: &Spectrogram,
     path: &str,
     height_limit: usize
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -10,9 +11,8 @@ pub fn save_spectrogram_image(
         return Ok(());
     }
 
-    let width = spectrogram.len();
-    // Assuming all time slices have the same number of frequency bins
-    let full_height = spectrogram[0].len();
+    let width = spectrogram.frames();
+    let full_height = spectrogram.bins();
 
     // We might crop the height because high frequencies (top of FFT) are often empty/noise
     let height = cmp::min(full_height, height_limit);
@@ -25,10 +25,12 @@ pub fn save_spectrogram_image(
     let mut min_val = f32::INFINITY;
 
     // Pre-calculate log magnitudes for better visualization
+    // dB and log1p spectrograms are already logarithmic and are drawn as they are
+    let already_log = spectrogram.scale.is_logarithmic();
     let log_spec: Vec<Vec<f32>> = spectrogram.iter()
         .map(|col| {
             col.iter().take(height).map(|&x| {
-                let log_x = (x + 1e-6).ln(); // Avoid log(0)
+                let log_x = if already_log { x } else { (x + 1e-6).ln() }; // Avoid log(0)
                 if log_x > max_val { max_val = log_x; }
                 if log_x < min_val { min_val = log_x; }
                 log_x
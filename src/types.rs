@@ -1,10 +1,30 @@
 pub mod types{
+    use crate::create_spectogram::SpectrogramUnits;
 
     
     pub struct SpectrogramPoint {
         pub(crate) freq_bin: usize,
         pub(crate) magnitude: f32,
         pub(crate) time_idx: usize,
+        /// Fractional bin, equal to `freq_bin` unless the peak was interpolated
+        pub(crate) freq_pos: f32,
+        /// Fractional frame, equal to `time_idx` unless the peak was interpolated
+        pub(crate) time_pos: f32,
+    }
+
+    impl SpectrogramPoint {
+        /// A peak sitting exactly on a bin and a frame.
+        pub fn new(freq_bin: usize, magnitude: f32, time_idx: usize) -> Self {
+            Self { freq_bin, magnitude, time_idx, freq_pos: freq_bin as f32, time_pos: time_idx as f32 }
+        }
+
+        pub fn frequency_hz(&self, units: &SpectrogramUnits) -> f32 {
+            units.bin_to_hz(self.freq_pos)
+        }
+
+        pub fn time_seconds(&self, units: &SpectrogramUnits) -> f32 {
+            units.frame_to_seconds(self.time_pos)
+        }
     }
 
     
@@ -12,6 +32,29 @@ pub mod types{
     pub struct Fingerprint {
         pub(crate) hash: u64,
         pub(crate) time_offset: usize, // The absolute time of the anchor
+        /// How far a fuzzy query hash was perturbed from the exact one, 0 if exact
+        pub(crate) distance: u32,
+    }
+
+    impl Fingerprint {
+        pub fn new(hash: u64, time_offset: usize) -> Self {
+            Self { hash, time_offset, distance: 0 }
+        }
+
+        /// A query hash perturbed by `distance` steps from the exact one.
+        pub fn fuzzy(hash: u64, time_offset: usize, distance: u32) -> Self {
+            Self { hash, time_offset, distance }
+        }
+
+        /// Vote of a matching hash, fuzzy hits count less than exact ones.
+        pub fn weight(&self) -> f32 {
+            1.0 / (1.0 + self.distance as f32)
+        }
+
+        /// Time of the anchor peak.
+        pub fn time_seconds(&self, units: &SpectrogramUnits) -> f32 {
+            units.frame_to_seconds(self.time_offset as f32)
+        }
     }
 
     #[derive(Hash)]